@@ -0,0 +1,498 @@
+use crate::cache::CACHE_DIR_ENV;
+use crate::commands::CHANGED_PATHS_ENV;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+#[cfg(feature = "jj-lib")]
+pub mod jj_lib;
+#[cfg(test)]
+pub mod memory;
+
+const JJ_HUNK_TOOL_ARG: &str = "--tool=jj-hunk";
+const JJ_HUNK_PROGRAM_KEY: &str = "merge-tools.jj-hunk.program";
+const JJ_HUNK_EDIT_ARGS_KEY: &str = "merge-tools.jj-hunk.edit-args";
+const SNAPSHOT_TOOL: &str = "jj-hunk-snapshot";
+const IGNORE_WORKING_COPY_ARG: &str = "--ignore-working-copy";
+
+const CHANGED_PATHS_TEMPLATE: &str =
+    r#"self.source().path().display() ++ "\n" ++ self.target().path().display() ++ "\n""#;
+const SUMMARY_TEMPLATE: &str = r#""{\"status\":" ++ self.status().escape_json() ++ ",\"path\":" ++ self.path().display().escape_json() ++ ",\"source\":" ++ self.source().path().display().escape_json() ++ ",\"target\":" ++ self.target().path().display().escape_json() ++ "}\n""#;
+
+/// One changed path of a revision, as reported by `jj diff`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiffSummaryEntry {
+    pub status: String,
+    pub path: String,
+    #[serde(default)]
+    pub source: String,
+    #[serde(default)]
+    pub target: String,
+}
+
+/// A jj command that rewrites history using a hunk selection.
+#[derive(Debug, Clone, Copy)]
+pub enum SelectionCommand<'a> {
+    Split {
+        message: &'a str,
+        rev: Option<&'a str>,
+    },
+    Commit {
+        message: &'a str,
+    },
+    Squash {
+        rev: Option<&'a str>,
+    },
+}
+
+impl SelectionCommand<'_> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SelectionCommand::Split { .. } => "split",
+            SelectionCommand::Commit { .. } => "commit",
+            SelectionCommand::Squash { .. } => "squash",
+        }
+    }
+
+    pub fn rev(&self) -> Option<&str> {
+        match self {
+            SelectionCommand::Split { rev, .. } | SelectionCommand::Squash { rev } => *rev,
+            SelectionCommand::Commit { .. } => None,
+        }
+    }
+}
+
+/// Everything jj-hunk needs from the repository.
+///
+/// Revisions are revset strings; `None` means the working copy. Parents are
+/// addressed as `(<rev>)^`, or `@-` for the working copy. Reads may happen
+/// from several threads at once.
+pub trait VcsBackend: Sync {
+    /// Changed paths of `rev` compared to its parent, limited to the jj
+    /// fileset expressions in `paths` (all paths when empty).
+    ///
+    /// Snapshots the working copy; the reads that follow do not.
+    fn diff_summary(&self, rev: Option<&str>, paths: &[String]) -> Result<Vec<DiffSummaryEntry>>;
+
+    /// Contents of `path` at `rev`, or empty if it does not exist there.
+    ///
+    /// Reads the repo as of the last `diff_summary`, so concurrent reads never
+    /// race to snapshot the working copy.
+    fn read_file(&self, rev: Option<&str>, path: &str) -> Vec<u8>;
+
+    /// Materialize both sides of the diff of `rev` at once, limited to the
+    /// filesets in `paths` like `diff_summary`.
+    ///
+    /// Reads the repo as of the last `diff_summary`, like `read_file`.
+    /// Backends without a faster path return an error and callers read files
+    /// one by one.
+    fn materialize_diff(&self, _rev: Option<&str>, _paths: &[String]) -> Result<TreeSnapshot> {
+        anyhow::bail!("materializing diffs is not supported by this backend")
+    }
+
+    /// Full id of the single commit `rev` resolves to.
+    fn commit_id(&self, rev: &str) -> Result<String>;
+
+    /// Id of the tree `rev` resolves to; equal for commits with the same
+    /// contents, so it survives rewording and rebasing without conflicts.
+    fn tree_id(&self, rev: &str) -> Result<String>;
+
+    /// Id of the latest operation, after snapshotting the working copy.
+    fn operation_id(&self) -> Result<String>;
+
+    /// Directory for jj-hunk state inside the repo (`.jj/jj-hunk`).
+    fn state_dir(&self) -> Result<PathBuf>;
+
+    /// Run `command`, keeping only the hunks selected by the spec file.
+    ///
+    /// `cache_dir` is the hunk cache the selection tool may use.
+    fn run_selection(
+        &mut self,
+        command: SelectionCommand,
+        spec_path: &Path,
+        cache_dir: Option<&Path>,
+    ) -> Result<()>;
+
+    /// Restore the repo to the state after `operation`.
+    fn restore_operation(&mut self, operation: &str) -> Result<()>;
+}
+
+/// The backend for the repo in the current directory.
+///
+/// With the `jj-lib` feature the repo is read in-process whenever jj-lib can
+/// load it; otherwise, and without the feature, `cli` spawns jj.
+pub fn open(cli: CliBackend) -> Box<dyn VcsBackend> {
+    #[cfg(feature = "jj-lib")]
+    if let Some(backend) = std::env::current_dir()
+        .ok()
+        .and_then(|cwd| self::jj_lib::JjLibBackend::load(&cwd).ok())
+    {
+        return Box::new(backend);
+    }
+    Box::new(cli)
+}
+
+/// Backend spawning the `jj` CLI and parsing its output.
+#[derive(Debug, Default)]
+pub struct CliBackend {
+    quiet: bool,
+    tool_config: Option<Vec<String>>,
+}
+
+impl CliBackend {
+    /// A backend whose jj processes never touch our stdin/stdout, and which
+    /// remembers the jj-hunk tool configuration between commands.
+    pub fn quiet() -> Self {
+        Self {
+            quiet: true,
+            tool_config: None,
+        }
+    }
+
+    fn jj(&self) -> Command {
+        let mut command = Command::new("jj");
+        if self.quiet {
+            command.stdin(Stdio::null()).stdout(std::io::stderr());
+        }
+        command
+    }
+
+    fn tool_config_args(&mut self) -> Result<Vec<String>> {
+        if let Some(args) = &self.tool_config {
+            return Ok(args.clone());
+        }
+        let args = jj_hunk_tool_config_args()?;
+        if self.quiet {
+            self.tool_config = Some(args.clone());
+        }
+        Ok(args)
+    }
+}
+
+impl VcsBackend for CliBackend {
+    fn diff_summary(&self, rev: Option<&str>, paths: &[String]) -> Result<Vec<DiffSummaryEntry>> {
+        let mut diff_args = vec!["diff", "--template", SUMMARY_TEMPLATE];
+        if let Some(rev) = rev {
+            diff_args.push("-r");
+            diff_args.push(rev);
+        }
+        if !paths.is_empty() {
+            diff_args.push("--");
+            diff_args.extend(paths.iter().map(String::as_str));
+        }
+
+        let output = Command::new("jj")
+            .args(&diff_args)
+            .output()
+            .context("Failed to run jj diff")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("jj diff failed: {}", stderr.trim());
+        }
+
+        let summary = String::from_utf8_lossy(&output.stdout);
+        let mut entries = Vec::new();
+        for (index, line) in summary.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: DiffSummaryEntry = serde_json::from_str(line)
+                .with_context(|| format!("Failed to parse diff summary line {}", index + 1))?;
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+
+    fn read_file(&self, rev: Option<&str>, path: &str) -> Vec<u8> {
+        let mut args = vec!["file", "show", IGNORE_WORKING_COPY_ARG];
+        if let Some(rev) = rev {
+            args.push("-r");
+            args.push(rev);
+        }
+        args.push(path);
+
+        Command::new("jj")
+            .args(&args)
+            .output()
+            .map(|o| o.stdout)
+            .unwrap_or_default()
+    }
+
+    fn materialize_diff(&self, rev: Option<&str>, paths: &[String]) -> Result<TreeSnapshot> {
+        // A fresh, private directory: a predictable name in a shared temp dir
+        // could be planted or symlinked by another user.
+        let root = tempfile::Builder::new()
+            .prefix("jj-hunk-")
+            .suffix(".snapshot")
+            .tempdir()
+            .context("Failed to create snapshot directory")?;
+        let snapshot = TreeSnapshot {
+            left: root.path().join("left"),
+            right: root.path().join("right"),
+            root,
+        };
+
+        let program = std::env::current_exe()
+            .context("Failed to determine current jj-hunk executable path")?;
+        let program = program
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("jj-hunk executable path is not valid UTF-8"))?;
+        let dest = snapshot
+            .root
+            .path()
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("temporary directory path is not valid UTF-8"))?;
+
+        let mut command = self.jj();
+        command
+            .arg("--config")
+            .arg(format!(
+                "merge-tools.{SNAPSHOT_TOOL}.program={}",
+                toml_string(program)
+            ))
+            .arg("--config")
+            .arg(format!(
+                r#"merge-tools.{SNAPSHOT_TOOL}.diff-args=["snapshot", "$left", "$right", {}]"#,
+                toml_string(dest)
+            ))
+            .args(["diff", IGNORE_WORKING_COPY_ARG, "--tool", SNAPSHOT_TOOL]);
+        if let Some(rev) = rev {
+            command.args(["-r", rev]);
+        }
+        if !paths.is_empty() {
+            command.arg("--").args(paths);
+        }
+        let output = command
+            .stdout(Stdio::null())
+            .output()
+            .context("Failed to run jj diff --tool")?;
+
+        // Older jj versions reject the config or the tool; the caller then
+        // reads files one by one.
+        if !output.status.success() || !snapshot.left.is_dir() || !snapshot.right.is_dir() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("jj diff --tool failed: {}", stderr.trim());
+        }
+        Ok(snapshot)
+    }
+
+    fn commit_id(&self, rev: &str) -> Result<String> {
+        let output = jj_stdout(&["log", "-r", rev, "--no-graph", "-T", r#"commit_id ++ "\n""#])?;
+        let mut ids = output.lines().filter(|line| !line.trim().is_empty());
+        let id = ids
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Revision {rev} did not resolve to a commit"))?;
+        if ids.next().is_some() {
+            anyhow::bail!("Revision {rev} must resolve to a single commit");
+        }
+        Ok(id.trim().to_string())
+    }
+
+    fn tree_id(&self, rev: &str) -> Result<String> {
+        // Templates do not expose tree ids, so ask the backing git repo for
+        // the tree of the commit.
+        let commit = self.commit_id(rev)?;
+        let git_dir = jj_stdout(&["git", "root"])?;
+        let output = Command::new("git")
+            .arg("--git-dir")
+            .arg(git_dir.trim())
+            .args(["rev-parse", &format!("{commit}^{{tree}}")])
+            .output()
+            .context("Failed to run git rev-parse")?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("git rev-parse failed: {}", stderr.trim());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    fn operation_id(&self) -> Result<String> {
+        // Snapshot the working copy so operation ids reflect on-disk edits.
+        jj_stdout(&["log", "-r", "@", "--no-graph", "-T", "\"\""])?;
+        let output = jj_stdout(&[
+            "op",
+            "log",
+            "--no-graph",
+            "--limit",
+            "1",
+            "-T",
+            r#"id ++ "\n""#,
+        ])?;
+        Ok(output.trim().to_string())
+    }
+
+    fn state_dir(&self) -> Result<PathBuf> {
+        let root = jj_stdout(&["root"])?;
+        Ok(Path::new(root.trim()).join(".jj").join("jj-hunk"))
+    }
+
+    fn run_selection(
+        &mut self,
+        command: SelectionCommand,
+        spec_path: &Path,
+        cache_dir: Option<&Path>,
+    ) -> Result<()> {
+        let args = match command {
+            SelectionCommand::Split { message, rev } => {
+                let mut args = vec!["split", JJ_HUNK_TOOL_ARG, "-m", message];
+                if let Some(rev) = rev {
+                    args.push("-r");
+                    args.push(rev);
+                }
+                args
+            }
+            SelectionCommand::Commit { message } => {
+                vec!["commit", "-i", JJ_HUNK_TOOL_ARG, "-m", message]
+            }
+            SelectionCommand::Squash { rev } => {
+                let mut args = vec!["squash", "-i", JJ_HUNK_TOOL_ARG];
+                if let Some(rev) = rev {
+                    args.push("-r");
+                    args.push(rev);
+                }
+                args
+            }
+        };
+
+        // Tell the selection tool which paths changed, so it skips walking
+        // and comparing both trees.
+        let mut changed_paths = tempfile::Builder::new()
+            .prefix("jj-hunk-")
+            .suffix(".paths")
+            .tempfile()
+            .context("Failed to create changed paths file")?;
+        changed_paths.write_all(changed_paths_of(command.rev())?.as_bytes())?;
+
+        let config_args = self.tool_config_args()?;
+        let mut jj = self.jj();
+        jj.args(&config_args)
+            .args(&args)
+            .env("JJ_HUNK_SELECTION", spec_path)
+            .env(CHANGED_PATHS_ENV, changed_paths.path());
+        if let Some(dir) = cache_dir {
+            jj.env(CACHE_DIR_ENV, dir);
+        }
+        let status = jj.status().context("Failed to run jj")?;
+
+        if !status.success() {
+            anyhow::bail!("jj command failed");
+        }
+        Ok(())
+    }
+
+    fn restore_operation(&mut self, operation: &str) -> Result<()> {
+        let status = self
+            .jj()
+            .args(["op", "restore", operation])
+            .status()
+            .context("Failed to run jj op restore")?;
+        if !status.success() {
+            anyhow::bail!("jj op restore failed");
+        }
+        Ok(())
+    }
+}
+
+/// Both sides of a diff, copied to a temporary directory by `jj diff --tool`.
+///
+/// Only changed files are materialized. Paths that are missing (e.g. symlinks
+/// or renames jj did not materialize) fall back to `jj file show`.
+pub struct TreeSnapshot {
+    /// Removed when the snapshot is dropped.
+    root: tempfile::TempDir,
+    left: PathBuf,
+    right: PathBuf,
+}
+
+impl TreeSnapshot {
+    pub fn read_before(&self, path: &str) -> Option<Vec<u8>> {
+        read_regular_file(&self.left.join(path))
+    }
+
+    pub fn read_after(&self, path: &str) -> Option<Vec<u8>> {
+        read_regular_file(&self.right.join(path))
+    }
+}
+
+fn read_regular_file(file: &Path) -> Option<Vec<u8>> {
+    if !fs::symlink_metadata(file).ok()?.is_file() {
+        return None;
+    }
+    fs::read(file).ok()
+}
+
+/// Source and target path of every change in `rev`, one per line and
+/// relative to the workspace root like the diff editor's `$left`/`$right`.
+fn changed_paths_of(rev: Option<&str>) -> Result<String> {
+    let root = jj_stdout(&["root"])?;
+    jj_stdout_in(
+        Path::new(root.trim()),
+        &[
+            "diff",
+            "--template",
+            CHANGED_PATHS_TEMPLATE,
+            "-r",
+            rev.unwrap_or("@"),
+        ],
+    )
+}
+
+fn jj_stdout(args: &[&str]) -> Result<String> {
+    jj_stdout_in(Path::new("."), args)
+}
+
+/// Like `jj_stdout`, running jj in `dir`.
+fn jj_stdout_in(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("jj")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .with_context(|| format!("Failed to run jj {}", args[0]))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj {} failed: {}", args[0], stderr.trim());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn jj_hunk_tool_config_args() -> Result<Vec<String>> {
+    let mut args = Vec::new();
+
+    if !jj_config_key_exists(JJ_HUNK_PROGRAM_KEY) {
+        let program = std::env::current_exe()
+            .context("Failed to determine current jj-hunk executable path")?;
+        let program = program
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("jj-hunk executable path is not valid UTF-8"))?;
+        args.push("--config".to_string());
+        args.push(format!("{JJ_HUNK_PROGRAM_KEY}={}", toml_string(program)));
+    }
+
+    if !jj_config_key_exists(JJ_HUNK_EDIT_ARGS_KEY) {
+        args.push("--config".to_string());
+        args.push(format!(
+            r#"{JJ_HUNK_EDIT_ARGS_KEY}=["select", "$left", "$right"]"#
+        ));
+    }
+
+    Ok(args)
+}
+
+fn jj_config_key_exists(key: &str) -> bool {
+    Command::new("jj")
+        .args(["config", "get", key])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+fn toml_string(value: &str) -> String {
+    serde_json::to_string(value).expect("string serialization should not fail")
+}
@@ -0,0 +1,730 @@
+use super::{DiffSummaryEntry, SelectionCommand, VcsBackend};
+use crate::cache::HunkCache;
+use crate::commands::{file_choice, report_relocation, select_hunks, FileChoice};
+use crate::spec::Spec;
+use anyhow::{Context, Result};
+use jj_lib::backend::{MergedTreeId, TreeValue};
+use jj_lib::commit::Commit;
+use jj_lib::config::{ConfigGetResultExt, ConfigLayer, ConfigSource, StackedConfig};
+use jj_lib::conflicts::{
+    materialize_merge_result_to_bytes, materialize_tree_value, ConflictMarkerStyle,
+    MaterializedTreeValue,
+};
+use jj_lib::fileset::{self, FilesetDiagnostics, FilesetExpression};
+use jj_lib::fsmonitor::FsmonitorSettings;
+use jj_lib::gitignore::GitIgnoreFile;
+use jj_lib::matchers::{EverythingMatcher, Matcher};
+use jj_lib::merge::Merge;
+use jj_lib::merged_tree::{MergedTree, MergedTreeBuilder, TreeDiffIterator};
+use jj_lib::object_id::ObjectId;
+use jj_lib::op_store::OperationId;
+use jj_lib::ref_name::WorkspaceNameBuf;
+use jj_lib::repo::{ReadonlyRepo, Repo, StoreFactories};
+use jj_lib::repo_path::{RepoPath, RepoPathUiConverter};
+use jj_lib::revset::{
+    self, DefaultSymbolResolver, RevsetAliasesMap, RevsetDiagnostics, RevsetExtensions,
+    RevsetParseContext, RevsetWorkspaceContext,
+};
+use jj_lib::rewrite::merge_commit_trees;
+use jj_lib::settings::{HumanByteSize, UserSettings};
+use jj_lib::store::Store;
+use jj_lib::working_copy::{CheckoutOptions, SnapshotOptions};
+use jj_lib::workspace::{default_working_copy_factories, Workspace};
+use pollster::FutureExt as _;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Largest untracked file snapshotted by default, like jj's own default.
+const DEFAULT_MAX_NEW_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Backend reading and rewriting the repo in-process through jj-lib.
+///
+/// The working copy is snapshotted by `diff_summary`, `operation_id` and
+/// selection commands; other reads reuse the repo as of the last snapshot.
+/// Only the config files jj-lib knows about are read: jj-cli's built-in
+/// revset aliases such as `trunk()` are unavailable, and colocated git refs
+/// are only exported by the next jj command.
+pub struct JjLibBackend {
+    workspace: Mutex<Workspace>,
+    settings: UserSettings,
+    aliases: RevsetAliasesMap,
+    workspace_name: WorkspaceNameBuf,
+    path_converter: RepoPathUiConverter,
+    conflict_marker_style: ConflictMarkerStyle,
+    repo: Mutex<Option<Arc<ReadonlyRepo>>>,
+}
+
+impl JjLibBackend {
+    /// Load the workspace containing `cwd`; paths are relative to `cwd`.
+    pub fn load(cwd: &Path) -> Result<Self> {
+        let cwd = cwd
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {}", cwd.display()))?;
+        let root = cwd
+            .ancestors()
+            .find(|dir| dir.join(".jj").is_dir())
+            .ok_or_else(|| anyhow::anyhow!("There is no jj repo in {}", cwd.display()))?
+            .to_path_buf();
+        let settings = load_settings(&root)?;
+        let workspace = Workspace::load(
+            &settings,
+            &root,
+            &StoreFactories::default(),
+            &default_working_copy_factories(),
+        )
+        .context("Failed to load jj workspace")?;
+
+        let mut aliases = RevsetAliasesMap::new();
+        if let Ok(table) = settings.get_table("revset-aliases") {
+            for (decl, value) in table {
+                if let Some(defn) = value.as_str() {
+                    aliases
+                        .insert(&decl, defn)
+                        .map_err(|err| anyhow::anyhow!("Invalid revset alias {decl}: {err}"))?;
+                }
+            }
+        }
+        let conflict_marker_style = settings
+            .get("ui.conflict-marker-style")
+            .optional()?
+            .unwrap_or_default();
+
+        Ok(Self {
+            workspace_name: workspace.workspace_name().to_owned(),
+            workspace: Mutex::new(workspace),
+            settings,
+            aliases,
+            path_converter: RepoPathUiConverter::Fs { cwd, base: root },
+            conflict_marker_style,
+            repo: Mutex::new(None),
+        })
+    }
+
+    /// The repo as of the last snapshot, snapshotting if there was none.
+    fn repo(&self) -> Result<Arc<ReadonlyRepo>> {
+        if let Some(repo) = self.repo.lock().unwrap().as_ref() {
+            return Ok(Arc::clone(repo));
+        }
+        self.snapshot()
+    }
+
+    /// Load the latest operation and record on-disk edits in the working-copy
+    /// commit, like jj does at the start of each command.
+    fn snapshot(&self) -> Result<Arc<ReadonlyRepo>> {
+        let mut workspace = self.workspace.lock().unwrap();
+        let mut repo = workspace.repo_loader().load_at_head()?;
+        let Some(wc_commit_id) = repo.view().get_wc_commit_id(&self.workspace_name).cloned() else {
+            return self.remember(repo);
+        };
+        let wc_commit = repo.store().get_commit(&wc_commit_id)?;
+
+        let base_ignores = self.base_ignores(repo.store())?;
+        let auto_track = self
+            .settings
+            .get_string("snapshot.auto-track")
+            .optional()?
+            .unwrap_or_else(|| "all()".to_string());
+        let start_tracking_matcher = self.fileset_matcher(&[auto_track])?;
+        let max_new_file_size = self
+            .settings
+            .get_value_with("snapshot.max-new-file-size", HumanByteSize::try_from)
+            .optional()?
+            .map_or(DEFAULT_MAX_NEW_FILE_SIZE, |size| size.0);
+        let options = SnapshotOptions {
+            base_ignores,
+            fsmonitor_settings: FsmonitorSettings::from_settings(&self.settings)?,
+            progress: None,
+            start_tracking_matcher: start_tracking_matcher.as_ref(),
+            max_new_file_size: if max_new_file_size == 0 {
+                u64::MAX
+            } else {
+                max_new_file_size
+            },
+            conflict_marker_style: self.conflict_marker_style,
+        };
+
+        let mut locked = workspace.start_working_copy_mutation()?;
+        if locked.locked_wc().old_tree_id() != wc_commit.tree_id() {
+            anyhow::bail!("The working copy is stale; run `jj workspace update-stale` first");
+        }
+        let (tree_id, _) = locked.locked_wc().snapshot(&options)?;
+        if tree_id != *wc_commit.tree_id() {
+            let mut tx = repo.start_transaction();
+            tx.set_is_snapshot(true);
+            tx.repo_mut()
+                .rewrite_commit(&wc_commit)
+                .set_tree_id(tree_id)
+                .write()?;
+            tx.repo_mut().rebase_descendants()?;
+            repo = tx.commit("snapshot working copy")?;
+        }
+        locked.finish(repo.op_id().clone())?;
+        self.remember(repo)
+    }
+
+    fn remember(&self, repo: Arc<ReadonlyRepo>) -> Result<Arc<ReadonlyRepo>> {
+        *self.repo.lock().unwrap() = Some(Arc::clone(&repo));
+        Ok(repo)
+    }
+
+    /// Ignore patterns outside the working copy: the user's global git
+    /// ignores and, for git-backed repos, `info/exclude`.
+    fn base_ignores(&self, store: &Store) -> Result<Arc<GitIgnoreFile>> {
+        let mut ignores = GitIgnoreFile::empty();
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+        if let Some(config_home) = config_home {
+            ignores = ignores.chain_with_file("", config_home.join("git").join("ignore"))?;
+        }
+        if let Ok(git) = jj_lib::git::get_git_backend(store) {
+            let exclude = git.git_repo_path().join("info").join("exclude");
+            ignores = ignores.chain_with_file("", exclude)?;
+        }
+        Ok(ignores)
+    }
+
+    fn fileset_matcher(&self, filesets: &[String]) -> Result<Box<dyn Matcher>> {
+        let expressions = filesets
+            .iter()
+            .map(|text| {
+                fileset::parse_maybe_bare(
+                    &mut FilesetDiagnostics::new(),
+                    text,
+                    &self.path_converter,
+                )
+                .map_err(|err| anyhow::anyhow!("Invalid fileset {text:?}: {err}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(FilesetExpression::union_all(expressions).to_matcher())
+    }
+
+    /// The single commit `rev` resolves to in `repo`.
+    fn resolve(&self, repo: &ReadonlyRepo, rev: &str) -> Result<Commit> {
+        let extensions = RevsetExtensions::default();
+        let context = RevsetParseContext {
+            aliases_map: &self.aliases,
+            local_variables: HashMap::new(),
+            user_email: self.settings.user_email(),
+            date_pattern_context: chrono::Local::now().into(),
+            extensions: &extensions,
+            workspace: Some(RevsetWorkspaceContext {
+                path_converter: &self.path_converter,
+                workspace_name: &self.workspace_name,
+            }),
+        };
+        let expression = revset::parse(&mut RevsetDiagnostics::new(), rev, &context)
+            .map_err(|err| anyhow::anyhow!("Invalid revset {rev:?}: {err}"))?;
+        let resolver = DefaultSymbolResolver::new(repo, extensions.symbol_resolvers());
+        let revset = expression
+            .resolve_user_expression(repo, &resolver)
+            .map_err(|err| anyhow::anyhow!("Failed to resolve {rev:?}: {err}"))?
+            .evaluate(repo)
+            .map_err(|err| anyhow::anyhow!("Failed to evaluate {rev:?}: {err}"))?;
+        let mut ids = revset.iter();
+        let id = ids
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Revset {rev:?} resolved to no revisions"))?
+            .map_err(|err| anyhow::anyhow!("Failed to evaluate {rev:?}: {err}"))?;
+        if ids.next().is_some() {
+            anyhow::bail!("Revset {rev:?} resolved to more than one revision");
+        }
+        Ok(repo.store().get_commit(&id)?)
+    }
+
+    /// The trees before and after `commit`; merges diff against their
+    /// merged parents.
+    fn trees(&self, repo: &ReadonlyRepo, commit: &Commit) -> Result<(MergedTree, MergedTree)> {
+        let parents = commit.parents().collect::<Result<Vec<_>, _>>()?;
+        Ok((merge_commit_trees(repo, &parents)?, commit.tree()?))
+    }
+
+    fn read(&self, rev: Option<&str>, path: &str) -> Result<Vec<u8>> {
+        let repo = self.repo()?;
+        let commit = self.resolve(&repo, rev.unwrap_or("@"))?;
+        let path = self.path_converter.parse_file_path(path)?;
+        let value = commit.tree()?.path_value(&path)?;
+        self.read_value(repo.store(), &path, value)
+    }
+
+    /// File contents of a tree value; conflicts are materialized with markers
+    /// and anything but files reads as empty.
+    fn read_value(
+        &self,
+        store: &Store,
+        path: &RepoPath,
+        value: Merge<Option<TreeValue>>,
+    ) -> Result<Vec<u8>> {
+        match materialize_tree_value(store, path, value).block_on()? {
+            MaterializedTreeValue::File(mut file) => Ok(file.read_all(path)?),
+            MaterializedTreeValue::FileConflict(file) => Ok(materialize_merge_result_to_bytes(
+                &file.contents,
+                self.conflict_marker_style,
+            )
+            .into()),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Text of one side of a change being split by hunks.
+    fn read_text(
+        &self,
+        store: &Store,
+        path: &RepoPath,
+        value: &Merge<Option<TreeValue>>,
+    ) -> Result<String> {
+        match value.as_resolved() {
+            Some(None) => Ok(String::new()),
+            Some(Some(TreeValue::File { .. })) => {
+                let bytes = self.read_value(store, path, value.clone())?;
+                String::from_utf8(bytes).with_context(|| {
+                    format!("{} is not valid UTF-8", path.as_internal_file_string())
+                })
+            }
+            _ => anyhow::bail!(
+                "Cannot select hunks of {}: it is not a regular file",
+                path.as_internal_file_string()
+            ),
+        }
+    }
+
+    /// Write the tree keeping what `spec` selects of the change from `before`
+    /// to `after`, the same way the `select` tool edits `$right`.
+    fn select_tree(
+        &self,
+        store: &Arc<Store>,
+        spec: &Spec,
+        cache: &HunkCache,
+        before: &MergedTree,
+        after: &MergedTree,
+    ) -> Result<MergedTreeId> {
+        let mut builder = MergedTreeBuilder::new(before.id());
+        for entry in TreeDiffIterator::new(before.as_merge(), after.as_merge(), &EverythingMatcher)
+        {
+            let (before_value, after_value) = entry.values?;
+            let filepath = entry.path.as_internal_file_string();
+            let selection = match file_choice(spec, filepath) {
+                FileChoice::Keep => {
+                    builder.set_or_remove(entry.path, after_value);
+                    continue;
+                }
+                FileChoice::Reset => continue,
+                FileChoice::Hunks(selection) => selection,
+            };
+            if after_value.is_absent() {
+                builder.set_or_remove(entry.path, after_value);
+                continue;
+            }
+
+            let before_text = self.read_text(store, &entry.path, &before_value)?;
+            let after_text = self.read_text(store, &entry.path, &after_value)?;
+            let (result, relocations) =
+                select_hunks(&before_text, &after_text, selection, spec.fuzzy, cache);
+            for relocation in &relocations {
+                report_relocation(filepath, relocation);
+            }
+            if result == after_text {
+                builder.set_or_remove(entry.path, after_value);
+                continue;
+            }
+            let executable = matches!(
+                after_value.as_resolved(),
+                Some(Some(TreeValue::File {
+                    executable: true,
+                    ..
+                }))
+            );
+            let id = store
+                .write_file(&entry.path, &mut result.as_bytes())
+                .block_on()?;
+            builder.set_or_remove(
+                entry.path,
+                Merge::normal(TreeValue::File { id, executable }),
+            );
+        }
+        Ok(builder.write_tree(store)?)
+    }
+
+    /// Publish `repo`'s new operation to the working copy, checking out the
+    /// working-copy commit if it changed.
+    fn update_working_copy(&self, old_wc: Option<&Commit>, repo: &Arc<ReadonlyRepo>) -> Result<()> {
+        let mut workspace = self.workspace.lock().unwrap();
+        let new_wc_id = repo.view().get_wc_commit_id(&self.workspace_name).cloned();
+        match (old_wc, new_wc_id) {
+            (Some(old_wc), Some(new_wc_id)) if *old_wc.id() != new_wc_id => {
+                let new_wc = repo.store().get_commit(&new_wc_id)?;
+                let options = CheckoutOptions {
+                    conflict_marker_style: self.conflict_marker_style,
+                };
+                workspace.check_out(
+                    repo.op_id().clone(),
+                    Some(old_wc.tree_id()),
+                    &new_wc,
+                    &options,
+                )?;
+            }
+            _ => {
+                let locked = workspace.start_working_copy_mutation()?;
+                locked.finish(repo.op_id().clone())?;
+            }
+        }
+        drop(workspace);
+        self.remember(Arc::clone(repo))?;
+        Ok(())
+    }
+}
+
+impl VcsBackend for JjLibBackend {
+    fn diff_summary(&self, rev: Option<&str>, paths: &[String]) -> Result<Vec<DiffSummaryEntry>> {
+        let repo = self.snapshot()?;
+        let commit = self.resolve(&repo, rev.unwrap_or("@"))?;
+        let (before, after) = self.trees(&repo, &commit)?;
+        let matcher: Box<dyn Matcher> = if paths.is_empty() {
+            Box::new(EverythingMatcher)
+        } else {
+            self.fileset_matcher(paths)?
+        };
+
+        let mut entries = Vec::new();
+        for entry in TreeDiffIterator::new(before.as_merge(), after.as_merge(), matcher.as_ref()) {
+            let (before_value, after_value) = entry.values?;
+            let status = if before_value.is_absent() {
+                "added"
+            } else if after_value.is_absent() {
+                "removed"
+            } else {
+                "modified"
+            };
+            let path = self.path_converter.format_file_path(&entry.path);
+            entries.push(DiffSummaryEntry {
+                status: status.to_string(),
+                source: path.clone(),
+                target: path.clone(),
+                path,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn read_file(&self, rev: Option<&str>, path: &str) -> Vec<u8> {
+        self.read(rev, path).unwrap_or_default()
+    }
+
+    fn commit_id(&self, rev: &str) -> Result<String> {
+        let repo = self.repo()?;
+        Ok(self.resolve(&repo, rev)?.id().hex())
+    }
+
+    fn tree_id(&self, rev: &str) -> Result<String> {
+        // Resolved trees on git-backed repos match the CLI backend's ids;
+        // conflicted ones list the id of every term.
+        let repo = self.repo()?;
+        let trees = self.resolve(&repo, rev)?.tree_id().to_merge();
+        Ok(match trees.as_resolved() {
+            Some(tree) => tree.hex(),
+            None => trees
+                .iter()
+                .map(ObjectId::hex)
+                .collect::<Vec<_>>()
+                .join("+"),
+        })
+    }
+
+    fn operation_id(&self) -> Result<String> {
+        Ok(self.snapshot()?.op_id().hex())
+    }
+
+    fn state_dir(&self) -> Result<PathBuf> {
+        let workspace = self.workspace.lock().unwrap();
+        Ok(workspace.workspace_root().join(".jj").join("jj-hunk"))
+    }
+
+    fn run_selection(
+        &mut self,
+        command: SelectionCommand,
+        spec_path: &Path,
+        cache_dir: Option<&Path>,
+    ) -> Result<()> {
+        let content = fs::read_to_string(spec_path)
+            .with_context(|| format!("Failed to read spec from {}", spec_path.display()))?;
+        let spec = Spec::from_str(&content)?;
+        let cache = HunkCache::new(cache_dir.map(Path::to_path_buf));
+
+        let repo = self.snapshot()?;
+        let store = Arc::clone(repo.store());
+        let old_wc = match repo.view().get_wc_commit_id(&self.workspace_name) {
+            Some(id) => Some(store.get_commit(id)?),
+            None => None,
+        };
+        let target = self.resolve(&repo, command.rev().unwrap_or("@"))?;
+        let (before, after) = self.trees(&repo, &target)?;
+        let selected = self.select_tree(&store, &spec, &cache, &before, &after)?;
+
+        let mut tx = repo.start_transaction();
+        let mut_repo = tx.repo_mut();
+        match command {
+            SelectionCommand::Split { message, .. } => {
+                let first = mut_repo
+                    .rewrite_commit(&target)
+                    .set_tree_id(selected)
+                    .set_description(message)
+                    .write()?;
+                let second = mut_repo
+                    .rewrite_commit(&target)
+                    .set_parents(vec![first.id().clone()])
+                    .set_tree_id(target.tree_id().clone())
+                    .generate_new_change_id()
+                    .write()?;
+                mut_repo.set_rewritten_commit(target.id().clone(), second.id().clone());
+            }
+            SelectionCommand::Commit { message } => {
+                let committed = mut_repo
+                    .rewrite_commit(&target)
+                    .set_tree_id(selected)
+                    .set_description(message)
+                    .write()?;
+                let new_wc = mut_repo
+                    .new_commit(vec![committed.id().clone()], target.tree_id().clone())
+                    .write()?;
+                mut_repo.edit(self.workspace_name.clone(), &new_wc)?;
+            }
+            SelectionCommand::Squash { .. } => {
+                let [parent_id] = target.parent_ids() else {
+                    anyhow::bail!("Cannot squash a merge commit into its parents");
+                };
+                let parent = store.get_commit(parent_id)?;
+                let squashed_everything = selected == *target.tree_id();
+                let description = if squashed_everything && parent.description().is_empty() {
+                    target.description().to_string()
+                } else {
+                    parent.description().to_string()
+                };
+                let new_parent = mut_repo
+                    .rewrite_commit(&parent)
+                    .set_tree_id(selected)
+                    .set_description(description)
+                    .write()?;
+                if squashed_everything {
+                    mut_repo.record_abandoned_commit(&target);
+                } else {
+                    mut_repo
+                        .rewrite_commit(&target)
+                        .set_parents(vec![new_parent.id().clone()])
+                        .write()?;
+                }
+            }
+        }
+        mut_repo.rebase_descendants()?;
+        let repo = tx.commit(format!("jj-hunk {}", command.name()))?;
+        self.update_working_copy(old_wc.as_ref(), &repo)
+    }
+
+    fn restore_operation(&mut self, operation: &str) -> Result<()> {
+        let repo = self.snapshot()?;
+        let old_wc = match repo.view().get_wc_commit_id(&self.workspace_name) {
+            Some(id) => Some(repo.store().get_commit(id)?),
+            None => None,
+        };
+        let id = OperationId::try_from_hex(operation)
+            .map_err(|_| anyhow::anyhow!("Invalid operation id {operation:?}"))?;
+        let target = repo.loader().load_operation(&id)?;
+
+        let mut tx = repo.start_transaction();
+        tx.repo_mut().set_view(target.view()?.store_view().clone());
+        let repo = tx.commit(format!("restore to operation {operation}"))?;
+        self.update_working_copy(old_wc.as_ref(), &repo)
+    }
+}
+
+/// jj-lib's defaults overlaid with the user's and the repo's config files,
+/// then `JJ_USER`/`JJ_EMAIL`.
+fn load_settings(root: &Path) -> Result<UserSettings> {
+    let mut config = StackedConfig::with_defaults();
+
+    let mut env_base = ConfigLayer::empty(ConfigSource::EnvBase);
+    if let Ok(user) = std::env::var("USER") {
+        env_base.set_value("operation.username", user)?;
+    }
+    if let Ok(host) = std::env::var("HOSTNAME") {
+        env_base.set_value("operation.hostname", host)?;
+    }
+    config.add_layer(env_base);
+
+    for path in user_config_paths() {
+        if path.is_dir() {
+            config.load_dir(ConfigSource::User, &path)?;
+        } else if path.is_file() {
+            config.load_file(ConfigSource::User, path)?;
+        }
+    }
+    let repo_config = root.join(".jj").join("repo").join("config.toml");
+    if repo_config.is_file() {
+        config.load_file(ConfigSource::Repo, repo_config)?;
+    }
+
+    let mut overrides = ConfigLayer::empty(ConfigSource::EnvOverrides);
+    if let Ok(name) = std::env::var("JJ_USER") {
+        overrides.set_value("user.name", name)?;
+    }
+    if let Ok(email) = std::env::var("JJ_EMAIL") {
+        overrides.set_value("user.email", email)?;
+    }
+    config.add_layer(overrides);
+
+    Ok(UserSettings::from_config(config)?)
+}
+
+/// `$JJ_CONFIG` if set, otherwise `~/.jjconfig.toml` and the jj directory
+/// under the config home.
+fn user_config_paths() -> Vec<PathBuf> {
+    if let Some(paths) = std::env::var_os("JJ_CONFIG") {
+        return std::env::split_paths(&paths).collect();
+    }
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|home| home.join(".config")));
+    let mut paths = Vec::new();
+    if let Some(home) = home {
+        paths.push(home.join(".jjconfig.toml"));
+    }
+    if let Some(config_home) = config_home {
+        paths.push(config_home.join("jj").join("config.toml"));
+        paths.push(config_home.join("jj").join("conf.d"));
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh repo in a temporary directory with `files` in its working copy.
+    fn repo(files: &[(&str, &str)]) -> (tempfile::TempDir, JjLibBackend) {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let settings = load_settings(dir.path()).expect("settings");
+        Workspace::init_simple(&settings, dir.path()).expect("init repo");
+        write(dir.path(), files);
+        let backend = JjLibBackend::load(dir.path()).expect("load repo");
+        (dir, backend)
+    }
+
+    fn write(root: &Path, files: &[(&str, &str)]) {
+        for (path, content) in files {
+            fs::write(root.join(path), content).expect("write file");
+        }
+    }
+
+    fn changed(backend: &JjLibBackend, rev: Option<&str>) -> Vec<(String, String)> {
+        let entries = backend.diff_summary(rev, &[]).expect("diff summary");
+        entries
+            .into_iter()
+            .map(|entry| (entry.status, entry.path))
+            .collect()
+    }
+
+    fn run(backend: &mut JjLibBackend, dir: &Path, command: SelectionCommand, spec: &str) {
+        let spec_path = dir.join(".jj").join("spec.json");
+        fs::write(&spec_path, spec).expect("write spec");
+        backend
+            .run_selection(command, &spec_path, None)
+            .expect("run selection");
+    }
+
+    fn pair(status: &str, path: &str) -> (String, String) {
+        (status.to_string(), path.to_string())
+    }
+
+    #[test]
+    fn split_keeps_selected_files_and_hunks_and_restores() {
+        let before = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+        let (dir, mut backend) = repo(&[("a.txt", before), ("b.txt", "two\n")]);
+        run(
+            &mut backend,
+            dir.path(),
+            SelectionCommand::Commit { message: "base" },
+            r#"{"files": {}, "default": "keep"}"#,
+        );
+        let operation = backend.operation_id().expect("operation id");
+
+        write(
+            dir.path(),
+            &[
+                ("a.txt", "one\n2\n3\n4\n5\n6\n7\n8\n9\nten\n"),
+                ("b.txt", "TWO\n"),
+                ("c.txt", "three\n"),
+            ],
+        );
+        assert_eq!(
+            changed(&backend, None),
+            vec![
+                pair("modified", "a.txt"),
+                pair("modified", "b.txt"),
+                pair("added", "c.txt")
+            ]
+        );
+        assert_eq!(backend.read_file(Some("@-"), "a.txt"), before.as_bytes());
+        let tree = backend.tree_id("@").expect("tree id");
+
+        run(
+            &mut backend,
+            dir.path(),
+            SelectionCommand::Split {
+                message: "first",
+                rev: None,
+            },
+            r#"{"files": {"a.txt": {"hunks": [0]}, "c.txt": {"action": "keep"}}, "default": "reset"}"#,
+        );
+
+        assert_eq!(
+            changed(&backend, Some("@-")),
+            vec![pair("modified", "a.txt"), pair("added", "c.txt")]
+        );
+        assert_eq!(
+            backend.read_file(Some("@-"), "a.txt"),
+            b"one\n2\n3\n4\n5\n6\n7\n8\n9\n10\n"
+        );
+        assert_eq!(
+            changed(&backend, None),
+            vec![pair("modified", "a.txt"), pair("modified", "b.txt")]
+        );
+        assert_eq!(backend.tree_id("@").expect("tree id"), tree);
+
+        backend.restore_operation(&operation).expect("restore");
+        assert_eq!(changed(&backend, None), Vec::new());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("b.txt")).unwrap(),
+            "two\n"
+        );
+    }
+
+    #[test]
+    fn squash_moves_the_selection_into_the_parent() {
+        let (dir, mut backend) = repo(&[("a.txt", "a\n")]);
+        run(
+            &mut backend,
+            dir.path(),
+            SelectionCommand::Commit { message: "base" },
+            r#"{"files": {}, "default": "keep"}"#,
+        );
+        let parent = backend.commit_id("@-").expect("parent");
+
+        write(dir.path(), &[("a.txt", "A\n"), ("b.txt", "b\n")]);
+        run(
+            &mut backend,
+            dir.path(),
+            SelectionCommand::Squash { rev: None },
+            r#"{"files": {"b.txt": {"action": "keep"}}, "default": "reset"}"#,
+        );
+
+        assert_ne!(backend.commit_id("@-").expect("parent"), parent);
+        assert_eq!(
+            changed(&backend, Some("@-")),
+            vec![pair("added", "a.txt"), pair("added", "b.txt")]
+        );
+        assert_eq!(backend.read_file(Some("@-"), "a.txt"), b"a\n");
+        assert_eq!(changed(&backend, None), vec![pair("modified", "a.txt")]);
+    }
+}
@@ -0,0 +1,198 @@
+use super::{DiffSummaryEntry, SelectionCommand, VcsBackend};
+use crate::diff::hex_encode;
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A selection command run against a `MemoryBackend`, with its spec.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedRun {
+    pub command: String,
+    pub rev: Option<String>,
+    pub spec: String,
+}
+
+/// In-memory repository for unit tests.
+///
+/// Revisions are named trees with an optional parent. The working copy is
+/// `@`; revsets `@-`, `<rev>-` and `(<rev>)^` resolve to parents. Commit ids
+/// are the revision names. Filesets may only be `glob:<pattern>` or a path
+/// (matching the path and everything below it).
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    trees: HashMap<String, BTreeMap<String, Vec<u8>>>,
+    parents: HashMap<String, String>,
+    operation: usize,
+    state_dir: Option<PathBuf>,
+    runs: Arc<Mutex<Vec<RecordedRun>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a revision `name` whose files are `files`.
+    pub fn with_revision(
+        mut self,
+        name: &str,
+        parent: Option<&str>,
+        files: &[(&str, &str)],
+    ) -> Self {
+        let tree = files
+            .iter()
+            .map(|(path, content)| (path.to_string(), content.as_bytes().to_vec()))
+            .collect();
+        self.trees.insert(name.to_string(), tree);
+        if let Some(parent) = parent {
+            self.parents.insert(name.to_string(), parent.to_string());
+        }
+        self
+    }
+
+    /// Keep jj-hunk state (the undo record) in `dir`.
+    pub fn with_state_dir(mut self, dir: &Path) -> Self {
+        self.state_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    /// Selection commands run so far; stays valid after the backend moves.
+    pub fn runs(&self) -> Arc<Mutex<Vec<RecordedRun>>> {
+        Arc::clone(&self.runs)
+    }
+
+    fn resolve(&self, rev: &str) -> Result<String> {
+        let rev = rev.trim();
+        let parent_of = rev.strip_suffix('-').map(str::to_string).or_else(|| {
+            rev.strip_suffix('^').map(|rev| {
+                rev.trim_start_matches('(')
+                    .trim_end_matches(')')
+                    .to_string()
+            })
+        });
+        let name = match parent_of {
+            Some(child) => {
+                let child = self.resolve(&child)?;
+                self.parents
+                    .get(&child)
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("Revision {child} has no parent"))?
+            }
+            None => rev.to_string(),
+        };
+        if !self.trees.contains_key(&name) {
+            anyhow::bail!("Revision {rev} did not resolve to a commit");
+        }
+        Ok(name)
+    }
+
+    fn tree(&self, rev: &str) -> Option<&BTreeMap<String, Vec<u8>>> {
+        self.trees.get(&self.resolve(rev).ok()?)
+    }
+}
+
+impl VcsBackend for MemoryBackend {
+    fn diff_summary(&self, rev: Option<&str>, paths: &[String]) -> Result<Vec<DiffSummaryEntry>> {
+        if let Some(fileset) = paths
+            .iter()
+            .find(|fileset| fileset.contains(['&', '|', '~', '(', ')']))
+        {
+            anyhow::bail!("MemoryBackend does not support the fileset {fileset:?}");
+        }
+        let in_filesets = |path: &str| {
+            paths.is_empty()
+                || paths
+                    .iter()
+                    .any(|fileset| match fileset.strip_prefix("glob:") {
+                        Some(pattern) => crate::commands::glob_match(pattern, path),
+                        None => {
+                            let prefix = fileset.trim_end_matches('/');
+                            path == prefix || path.starts_with(&format!("{prefix}/"))
+                        }
+                    })
+        };
+        let rev = rev.unwrap_or("@");
+        let after = self.tree(rev).cloned().unwrap_or_default();
+        let before = self.tree(&format!("({rev})^")).cloned().unwrap_or_default();
+
+        let changed: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+        let entries = changed
+            .into_iter()
+            .filter(|path| in_filesets(path))
+            .filter_map(|path| {
+                let status = match (before.get(path), after.get(path)) {
+                    (None, Some(_)) => "added",
+                    (Some(_), None) => "removed",
+                    (Some(old), Some(new)) if old != new => "modified",
+                    _ => return None,
+                };
+                Some(DiffSummaryEntry {
+                    status: status.to_string(),
+                    path: path.clone(),
+                    source: path.clone(),
+                    target: path.clone(),
+                })
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    fn read_file(&self, rev: Option<&str>, path: &str) -> Vec<u8> {
+        self.tree(rev.unwrap_or("@"))
+            .and_then(|tree| tree.get(path))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn commit_id(&self, rev: &str) -> Result<String> {
+        self.resolve(rev)
+    }
+
+    fn tree_id(&self, rev: &str) -> Result<String> {
+        let name = self.resolve(rev)?;
+        let mut hasher = Sha256::new();
+        for (path, content) in &self.trees[&name] {
+            hasher.update(path.as_bytes());
+            hasher.update(b"\0");
+            hasher.update(content);
+            hasher.update(b"\0");
+        }
+        Ok(format!("sha256:{}", hex_encode(&hasher.finalize())))
+    }
+
+    fn operation_id(&self) -> Result<String> {
+        Ok(format!("op{}", self.operation))
+    }
+
+    fn state_dir(&self) -> Result<PathBuf> {
+        self.state_dir
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("MemoryBackend has no state directory"))
+    }
+
+    fn run_selection(
+        &mut self,
+        command: SelectionCommand,
+        spec_path: &Path,
+        _cache_dir: Option<&Path>,
+    ) -> Result<()> {
+        self.runs.lock().unwrap().push(RecordedRun {
+            command: command.name().to_string(),
+            rev: command.rev().map(str::to_string),
+            spec: fs::read_to_string(spec_path)?,
+        });
+        self.operation += 1;
+        Ok(())
+    }
+
+    fn restore_operation(&mut self, operation: &str) -> Result<()> {
+        self.operation = operation
+            .strip_prefix("op")
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| anyhow::anyhow!("Unknown operation {operation}"))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,308 @@
+use crate::diff::{hex_encode, FileDiff, Hunk};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Bump when the cached format or the hunk computation changes.
+const CACHE_VERSION: &str = "2";
+
+/// Entries written longer ago than this are pruned.
+const MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// The oldest entries are pruned until the cache is at most this large.
+const MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Writers prune at most this often, going by the marker's modification time.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const PRUNE_MARKER: &str = "last-prune";
+
+/// Environment variable passing the cache directory to `jj-hunk select`.
+pub const CACHE_DIR_ENV: &str = "JJ_HUNK_CACHE_DIR";
+
+static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+
+/// Hunks of one file, as stored on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedDiff {
+    pub hunks: Vec<Hunk>,
+}
+
+/// On-disk cache of computed hunks.
+///
+/// Entries are keyed by the sha256 of both sides' contents, so they never go
+/// stale. Writers prune old entries and keep the cache under `MAX_BYTES`;
+/// `jj-hunk cache clear` removes everything. A cache without a directory
+/// caches nothing.
+#[derive(Debug, Clone, Default)]
+pub struct HunkCache {
+    dir: Option<PathBuf>,
+    pruned: Arc<AtomicBool>,
+}
+
+impl HunkCache {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            pruned: Arc::default(),
+        }
+    }
+
+    /// The cache directory handed down by the parent jj-hunk process, if any.
+    pub fn from_env() -> Self {
+        Self::new(std::env::var_os(CACHE_DIR_ENV).map(PathBuf::from))
+    }
+
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    pub fn key(before: &[u8], after: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(CACHE_VERSION.as_bytes());
+        hasher.update(b"\0before\0");
+        hasher.update(Sha256::digest(before));
+        hasher.update(b"after\0");
+        hasher.update(Sha256::digest(after));
+        hex_encode(&hasher.finalize())
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedDiff> {
+        let content = fs::read(self.entry_path(key)?).ok()?;
+        serde_json::from_slice(&content).ok()
+    }
+
+    /// Store `diff` under `key`; failures only cost a recomputation later.
+    pub fn put(&self, key: &str, diff: &CachedDiff) {
+        let Some(path) = self.entry_path(key) else {
+            return;
+        };
+        let Ok(content) = serde_json::to_vec(diff) else {
+            return;
+        };
+        // Write to a private file first so concurrent readers never see a
+        // partial entry.
+        let temp = path.with_extension(format!(
+            "{}-{}.tmp",
+            std::process::id(),
+            NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(&temp, content))
+            .and_then(|()| fs::rename(&temp, &path));
+        if written.is_err() {
+            fs::remove_file(&temp).ok();
+        }
+        self.prune_if_due();
+    }
+
+    /// Prune once per cache and process, unless another process did recently.
+    fn prune_if_due(&self) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        if self.pruned.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let marker = dir.join(PRUNE_MARKER);
+        let recent = fs::metadata(&marker)
+            .and_then(|meta| meta.modified())
+            .is_ok_and(|modified| {
+                modified
+                    .elapsed()
+                    .is_ok_and(|elapsed| elapsed < PRUNE_INTERVAL)
+            });
+        if recent || fs::write(&marker, b"").is_err() {
+            return;
+        }
+        self.prune(MAX_BYTES, MAX_AGE);
+    }
+
+    /// Remove entries and leftover temporary files older than `max_age`, then
+    /// the oldest entries until at most `max_bytes` remain.
+    fn prune(&self, max_bytes: u64, max_age: Duration) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let now = SystemTime::now();
+        let mut entries = Vec::new();
+        for entry in walkdir::WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            if !entry.file_type().is_file() || entry.file_name() == PRUNE_MARKER {
+                continue;
+            }
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            let modified = meta.modified().unwrap_or(now);
+            let expired = now.duration_since(modified).is_ok_and(|age| age > max_age);
+            if expired {
+                fs::remove_file(entry.path()).ok();
+            } else if is_entry(entry.path()) {
+                entries.push((modified, meta.len(), entry.into_path()));
+            }
+        }
+
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if total <= max_bytes {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                total -= len;
+            }
+        }
+    }
+
+    /// The diff of `before` and `after`, rebuilt from cached hunks when
+    /// possible.
+    pub fn file_diff(&self, before: &str, after: &str) -> FileDiff {
+        let key = Self::key(before.as_bytes(), after.as_bytes());
+        if let Some(diff) = self
+            .get(&key)
+            .and_then(|cached| FileDiff::from_hunks(before, cached.hunks))
+        {
+            return diff;
+        }
+        let diff = FileDiff::new(before, after);
+        self.put(
+            &key,
+            &CachedDiff {
+                hunks: diff.hunks().to_vec(),
+            },
+        );
+        diff
+    }
+
+    /// Delete every cached entry, returning how many were removed.
+    pub fn clear(&self) -> Result<usize> {
+        let Some(dir) = &self.dir else {
+            return Ok(0);
+        };
+        if !dir.exists() {
+            return Ok(0);
+        }
+        let count = walkdir::WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file() && is_entry(entry.path()))
+            .count();
+        fs::remove_dir_all(dir).with_context(|| format!("Failed to remove {}", dir.display()))?;
+        Ok(count)
+    }
+
+    fn entry_path(&self, key: &str) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        Some(dir.join(&key[..2]).join(format!("{key}.json")))
+    }
+}
+
+/// Whether `path` is a cache entry rather than a temporary file or marker.
+fn is_entry(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A cache in a fresh directory, removed when the `TempDir` drops.
+    fn temp_cache() -> (TempDir, HunkCache) {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = HunkCache::new(Some(dir.path().join("cache")));
+        (dir, cache)
+    }
+
+    #[test]
+    fn key_depends_on_both_sides() {
+        let key = HunkCache::key(b"a\n", b"b\n");
+        assert_eq!(key, HunkCache::key(b"a\n", b"b\n"));
+        assert_ne!(key, HunkCache::key(b"b\n", b"a\n"));
+        assert_ne!(key, HunkCache::key(b"a\n", b"c\n"));
+    }
+
+    #[test]
+    fn hunks_round_trip_through_disk_and_clear() {
+        let (_dir, cache) = temp_cache();
+        let computed = cache.file_diff("one\ntwo\n", "one\nTWO\n").into_hunks();
+        let key = HunkCache::key(b"one\ntwo\n", b"one\nTWO\n");
+
+        let stored = cache.get(&key).expect("entry should be cached");
+        assert_eq!(stored.hunks.len(), computed.len());
+        assert_eq!(stored.hunks[0].id, computed[0].id);
+        assert_eq!(
+            cache.file_diff("one\ntwo\n", "one\nTWO\n").hunks()[0].id,
+            computed[0].id
+        );
+
+        let stray = cache.dir().unwrap().join("stray.1-0.tmp");
+        fs::write(&stray, b"partial").unwrap();
+        assert_eq!(cache.clear().unwrap(), 1);
+        assert!(cache.get(&key).is_none());
+        assert_eq!(cache.clear().unwrap(), 0);
+    }
+
+    #[test]
+    fn prune_drops_expired_files_then_the_oldest_entries() {
+        let (_dir, cache) = temp_cache();
+        let now = SystemTime::now();
+        let age = |path: &Path, days: u64| {
+            let file = fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(now - Duration::from_secs(days * 24 * 60 * 60))
+                .unwrap();
+        };
+        let mut paths = Vec::new();
+        for (index, days) in [40, 3, 2, 1].into_iter().enumerate() {
+            let after = format!("{index}\n");
+            cache.file_diff("old\n", &after);
+            let key = HunkCache::key(b"old\n", after.as_bytes());
+            let path = cache.entry_path(&key).unwrap();
+            age(&path, days);
+            paths.push(path);
+        }
+        let stray = cache.dir().unwrap().join("stray.1-0.tmp");
+        fs::write(&stray, b"partial").unwrap();
+        age(&stray, 40);
+
+        let entry_size = fs::metadata(&paths[3]).unwrap().len();
+        cache.prune(2 * entry_size, MAX_AGE);
+
+        let kept: Vec<bool> = paths.iter().map(|path| path.exists()).collect();
+        assert_eq!(kept, [false, false, true, true]);
+        assert!(!stray.exists());
+        assert_eq!(cache.clear().unwrap(), 2);
+    }
+
+    #[test]
+    fn writes_prune_at_most_once_per_interval() {
+        let (_dir, cache) = temp_cache();
+        cache.file_diff("a\n", "b\n");
+        let marker = cache.dir().unwrap().join(PRUNE_MARKER);
+        assert!(marker.exists());
+
+        // A fresh marker stops other processes from pruning again.
+        let key = HunkCache::key(b"a\n", b"b\n");
+        let entry = cache.entry_path(&key).unwrap();
+        let file = fs::File::options().write(true).open(&entry).unwrap();
+        file.set_modified(SystemTime::now() - 2 * MAX_AGE).unwrap();
+        HunkCache::new(cache.dir().map(Path::to_path_buf)).file_diff("a\n", "c\n");
+        assert!(entry.exists());
+    }
+
+    #[test]
+    fn cache_without_dir_still_computes() {
+        let cache = HunkCache::default();
+        assert_eq!(cache.file_diff("a\n", "b\n").hunks().len(), 1);
+        assert_eq!(cache.clear().unwrap(), 0);
+    }
+}
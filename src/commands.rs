@@ -1,112 +1,315 @@
-use crate::diff::{apply_selected_hunks, get_hunks, Hunk, HunkSelection};
-use crate::spec::{Action, DefaultAction, FileSpec, Spec};
+use crate::backend::{self, CliBackend, DiffSummaryEntry, SelectionCommand, VcsBackend};
+use crate::cache::HunkCache;
+use crate::diff::{
+    digest_hunk_ids, file_lines, hex_encode, side_by_side, Hunk, HunkContext, HunkLine,
+    HunkSelection, LineKind, Relocation,
+};
+use crate::html::{self, ReportFile};
+use crate::patch::{self, ParsedFile, ParsedHunk, PatchFile};
+use crate::spec::{Action, DefaultAction, Expectation, FileSpec, Spec};
+use crate::template::{Template, TemplateFile};
 use anyhow::{Context, Result};
 use clap::ValueEnum;
+use regex::Regex;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use similar::{ChangeTag, TextDiff};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::io::Read;
-use std::path::Path;
-use std::process::Command;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
 use walkdir::WalkDir;
 
-const JJ_HUNK_TOOL_ARG: &str = "--tool=jj-hunk";
-const JJ_HUNK_PROGRAM_KEY: &str = "merge-tools.jj-hunk.program";
-const JJ_HUNK_EDIT_ARGS_KEY: &str = "merge-tools.jj-hunk.edit-args";
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
 pub enum ListFormat {
+    #[default]
     Json,
     Yaml,
     Text,
+    Ndjson,
+    Patch,
+    Markdown,
+    Html,
 }
 
-impl Default for ListFormat {
-    fn default() -> Self {
-        Self::Json
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
 pub enum ListGrouping {
+    #[default]
     None,
     Directory,
     Extension,
     Status,
 }
 
-impl Default for ListGrouping {
-    fn default() -> Self {
-        Self::None
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ListSort {
+    /// Path, in ascending order
+    Path,
+    /// Changed lines (removed + added), largest first
+    Size,
+    /// Hunk count, most first
+    Hunks,
+    /// Status, then path
+    Status,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HunkKind {
+    Insert,
+    Delete,
+    Replace,
+}
+
+impl HunkKind {
+    fn matches(self, hunk: &Hunk) -> bool {
+        let name = match self {
+            HunkKind::Insert => "insert",
+            HunkKind::Delete => "delete",
+            HunkKind::Replace => "replace",
+        };
+        hunk.hunk_type == name
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, ValueEnum, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
 pub enum BinaryMode {
     Skip,
+    #[default]
     Mark,
     Include,
 }
 
-impl Default for BinaryMode {
-    fn default() -> Self {
-        Self::Mark
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PageUnit {
+    #[default]
+    File,
+    Hunk,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
 pub enum ListMode {
+    #[default]
     Full,
     Files,
+    Stat,
     SpecTemplate,
 }
 
-impl Default for ListMode {
-    fn default() -> Self {
-        Self::Full
-    }
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
 pub struct ListOptions {
+    /// Revset to diff against its parent (default: the working copy)
     pub rev: Option<String>,
+    /// Include glob patterns
     pub include: Vec<String>,
+    /// Exclude glob patterns
     pub exclude: Vec<String>,
+    /// jj fileset expressions passed to `jj diff`, e.g. `glob:src/** & ~glob:**/*.snap`
+    ///
+    /// Only `list` narrows by these; commands taking a spec or patch list the
+    /// files it names instead.
+    pub paths: Vec<String>,
+    /// Group output by directory, extension, or status
     pub group: ListGrouping,
+    /// Sort files by path, size, hunk count, or status (default: diff order)
+    pub sort: Option<ListSort>,
+    /// Only list hunks of these types
+    pub types: Vec<HunkKind>,
+    /// Only list hunks changing at least N lines (removed + added)
+    pub min_lines: Option<usize>,
+    /// Only list hunks changing at most N lines (removed + added)
+    pub max_lines_changed: Option<usize>,
+    /// Only list hunks whose removed or added text matches this regex
+    pub grep: Option<String>,
+    /// Output format
     pub format: ListFormat,
+    /// Full hunks, file summaries, line stats, or a spec template
     pub mode: ListMode,
+    /// Make the spec template select hunk objects with their context, so it
+    /// still applies after the revision is edited
+    pub fuzzy: bool,
+    /// Spec to preview, as a JSON/YAML string or an object
+    #[serde(deserialize_with = "deserialize_spec_input")]
+    #[schemars(with = "Option<SpecInput>")]
     pub spec: Option<String>,
+    /// Read the spec to preview from a file (JSON or YAML)
     pub spec_file: Option<String>,
+    /// Binary handling
     pub binary: BinaryMode,
+    /// Cut each hunk's removed/added text to N bytes (ids stay valid)
     pub max_bytes: Option<usize>,
+    /// Cut each hunk's removed/added text to N lines (ids stay valid)
     pub max_lines: Option<usize>,
+    /// Color text output (auto: only on a terminal, unless NO_COLOR is set)
+    pub color: ColorChoice,
+    /// Show text hunks as two columns, removed beside added
+    pub side_by_side: bool,
+    /// Side-by-side width in characters (default: $COLUMNS, then 120)
+    pub width: Option<usize>,
+    /// Render each file (or hunk) through this template instead of `format`
+    pub template: Option<String>,
+    /// Read the template to render from a file
+    pub template_file: Option<String>,
+    /// List at most N files (or hunks, with `page_by: hunk`) per page
+    pub limit: Option<usize>,
+    /// Skip the first N files (or hunks) of the listing
+    pub offset: usize,
+    /// Whether `limit`, `offset` and `cursor` count files or hunks
+    pub page_by: PageUnit,
+    /// Resume where a previous page stopped (its `next_cursor`)
+    pub cursor: Option<String>,
+    /// Stop adding files (or hunks) before the page's JSON exceeds N bytes
+    pub max_output_bytes: Option<usize>,
 }
 
-impl Default for ListOptions {
-    fn default() -> Self {
+impl From<Option<&str>> for ListOptions {
+    fn from(rev: Option<&str>) -> Self {
         Self {
-            rev: None,
-            include: Vec::new(),
-            exclude: Vec::new(),
-            group: ListGrouping::default(),
-            format: ListFormat::default(),
-            mode: ListMode::default(),
-            spec: None,
-            spec_file: None,
-            binary: BinaryMode::default(),
-            max_bytes: None,
-            max_lines: None,
+            rev: rev.map(str::to_string),
+            ..Self::default()
         }
     }
 }
 
-impl From<Option<&str>> for ListOptions {
-    fn from(rev: Option<&str>) -> Self {
+/// Schema of spec parameters; see `deserialize_spec_input`.
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+pub enum SpecInput {
+    Text(String),
+    Inline(Spec),
+}
+
+/// Accept a spec either as a JSON/YAML string or as an inline object.
+pub fn deserialize_spec_input<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<serde_json::Value>::deserialize(deserializer)? {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(spec)) => Ok(Some(spec)),
+        Some(value @ serde_json::Value::Object(_)) => Ok(Some(value.to_string())),
+        Some(_) => Err(serde::de::Error::custom(
+            "spec must be a JSON/YAML string or an object",
+        )),
+    }
+}
+
+/// Length of the longest `--stat` histogram bar in text output.
+const STAT_BAR_WIDTH: usize = 40;
+
+/// Side-by-side width when neither `--width` nor `$COLUMNS` is set.
+const DEFAULT_COLUMNS: usize = 120;
+
+/// Upper bound on files read and diffed concurrently.
+const MAX_JOBS: usize = 8;
+
+/// Caches and the repository backend shared by consecutive commands.
+///
+/// One-shot CLI invocations use `Session::default()`, which caches nothing.
+/// `serve` keeps a single persistent session alive between requests.
+pub struct Session {
+    persistent: bool,
+    backend: Box<dyn VcsBackend>,
+    cache: Option<HunkCache>,
+    contents: HashMap<(String, String), Vec<u8>>,
+    entries: HashMap<EntryCacheKey, Option<FileEntry>>,
+}
+
+/// Listed files only depend on the two commits, the path and binary handling.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct EntryCacheKey {
+    before: String,
+    after: String,
+    path: String,
+    binary: BinaryMode,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::with_backend(backend::open(CliBackend::default()))
+    }
+}
+
+impl Session {
+    /// A session for long-running servers.
+    ///
+    /// File contents and hunks of the most recently listed commit pair are
+    /// cached by commit id, and spawned jj processes never touch our
+    /// stdin/stdout.
+    pub fn persistent() -> Self {
         Self {
-            rev: rev.map(str::to_string),
-            ..Self::default()
+            persistent: true,
+            ..Self::with_backend(backend::open(CliBackend::quiet()))
+        }
+    }
+
+    /// A non-caching session reading the repo through `backend`.
+    pub fn with_backend(backend: Box<dyn VcsBackend>) -> Self {
+        Self {
+            persistent: false,
+            backend,
+            cache: None,
+            contents: HashMap::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// The on-disk hunk cache under `.jj/jj-hunk/cache`, opened on first use.
+    fn hunk_cache(&mut self) -> HunkCache {
+        let backend = &self.backend;
+        self.cache
+            .get_or_insert_with(|| {
+                HunkCache::new(backend.state_dir().ok().map(|dir| dir.join("cache")))
+            })
+            .clone()
+    }
+
+    /// Resolve the commits being diffed, or `None` when not caching.
+    fn resolve_commits(&self, rev: Option<&str>) -> Option<(String, String)> {
+        if !self.persistent {
+            return None;
         }
+        let after = self.backend.commit_id(rev.unwrap_or("@")).ok()?;
+        let before = self.backend.commit_id(&format!("{after}-")).ok()?;
+        Some((before, after))
     }
+
+    /// Drop cached contents and files of every commit but `before` and
+    /// `after`, so a long-running server only holds the pair it last listed.
+    fn retain_commits(&mut self, before: &str, after: &str) {
+        self.contents
+            .retain(|(commit, _), _| commit == before || commit == after);
+        self.entries
+            .retain(|key, _| key.before == before && key.after == after);
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum ListResult {
+    Full(ListOutput),
+    Files(ListSummaryOutput),
+    Stat(ListStatOutput),
+    SpecTemplate(SpecTemplateOutput),
 }
 
 #[derive(Debug, Serialize)]
@@ -115,6 +318,8 @@ struct ListOutput {
     files: Option<Vec<FileEntry>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     groups: Option<Vec<ListGroup>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -123,7 +328,7 @@ struct ListGroup {
     files: Vec<FileEntry>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Clone)]
 struct FileEntry {
     path: String,
     status: String,
@@ -148,6 +353,8 @@ struct ListSummaryOutput {
     files: Option<Vec<FileSummary>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     groups: Option<Vec<ListSummaryGroup>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -170,244 +377,1472 @@ struct FileSummary {
 }
 
 #[derive(Debug, Serialize)]
-struct SpecTemplateOutput {
-    files: HashMap<String, SpecTemplateEntry>,
-    default: String,
+struct ListStatOutput {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<FileStat>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<Vec<ListStatGroup>>,
+    total: LineTotals,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
-#[serde(untagged)]
-enum SpecTemplateEntry {
-    Ids { ids: Vec<String> },
-    Action { action: String },
+struct ListStatGroup {
+    name: String,
+    #[serde(flatten)]
+    total: LineTotals,
+    files: Vec<FileStat>,
 }
 
-#[derive(Debug, Deserialize)]
-struct DiffSummaryEntry {
-    status: String,
+/// Changed files, hunks and lines of a group or of the whole listing.
+#[derive(Debug, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+struct LineTotals {
+    files: usize,
+    hunks: usize,
+    added: usize,
+    removed: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct FileStat {
     path: String,
-    #[serde(default)]
-    source: String,
-    #[serde(default)]
-    target: String,
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rename: Option<RenameInfo>,
+    added: usize,
+    removed: usize,
+    hunks: Vec<HunkStat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    binary: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated: Option<bool>,
 }
 
-/// List hunks in current working copy or a specific revision
-pub fn list<T>(options: T) -> Result<()>
-where
-    T: Into<ListOptions>,
-{
-    let options = options.into();
-    let spec = resolve_optional_spec(options.spec.as_deref(), options.spec_file.as_deref())?
-        .map(|content| Spec::from_str(&content))
-        .transpose()?;
+#[derive(Debug, Serialize)]
+struct HunkStat {
+    id: String,
+    index: usize,
+    #[serde(rename = "type")]
+    hunk_type: String,
+    added: usize,
+    removed: usize,
+}
 
-    let include = normalize_patterns(&options.include);
-    let exclude = normalize_patterns(&options.exclude);
+/// One line of `--format ndjson` output.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum NdjsonRecord<T> {
+    File {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+        #[serde(flatten)]
+        file: T,
+    },
+    Summary {
+        files: usize,
+        hunks: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        added: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        removed: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next_cursor: Option<String>,
+    },
+}
 
-    let summary_entries = read_diff_summary(options.rev.as_deref())?;
-    let (before_rev, after_rev) = resolve_revisions(options.rev.as_deref());
+impl From<FileEntry> for FileSummary {
+    fn from(file: FileEntry) -> Self {
+        Self {
+            path: file.path,
+            status: file.status,
+            rename: file.rename,
+            hunk_count: file.hunks.len(),
+            binary: file.binary,
+            truncated: file.truncated,
+        }
+    }
+}
 
-    let mut files = Vec::new();
+impl From<FileEntry> for FileStat {
+    fn from(file: FileEntry) -> Self {
+        let hunks: Vec<HunkStat> = file.hunks.into_iter().map(HunkStat::from).collect();
+        Self {
+            path: file.path,
+            status: file.status,
+            rename: file.rename,
+            added: hunks.iter().map(|hunk| hunk.added).sum(),
+            removed: hunks.iter().map(|hunk| hunk.removed).sum(),
+            hunks,
+            binary: file.binary,
+            truncated: file.truncated,
+        }
+    }
+}
 
-    for entry in summary_entries {
-        let path = primary_path(&entry);
-        if path.is_empty() {
-            continue;
+impl From<Hunk> for HunkStat {
+    fn from(hunk: Hunk) -> Self {
+        Self {
+            added: hunk.after_range.length,
+            removed: hunk.before_range.length,
+            id: hunk.id,
+            index: hunk.index,
+            hunk_type: hunk.hunk_type,
         }
+    }
+}
 
-        if !should_include_entry(&entry, &include, &exclude) {
-            continue;
+impl ListResult {
+    fn next_cursor(&self) -> Option<&str> {
+        match self {
+            ListResult::Full(output) => output.next_cursor.as_deref(),
+            ListResult::Files(summary) => summary.next_cursor.as_deref(),
+            ListResult::Stat(stat) => stat.next_cursor.as_deref(),
+            ListResult::SpecTemplate(_) => None,
         }
+    }
 
-        let decision = spec_decision(spec.as_ref(), &path);
-        if matches!(decision, SpecDecision::Skip) {
-            continue;
+    fn set_next_cursor(&mut self, cursor: Option<String>) {
+        match self {
+            ListResult::Full(output) => output.next_cursor = cursor,
+            ListResult::Files(summary) => summary.next_cursor = cursor,
+            ListResult::Stat(stat) => stat.next_cursor = cursor,
+            ListResult::SpecTemplate(_) => {}
         }
+    }
+}
 
-        let file_paths = file_paths_for_entry(&entry, &path);
-        let before_bytes = file_paths
-            .before
-            .as_deref()
-            .map(|p| read_jj_file(before_rev.as_deref(), p))
-            .unwrap_or_default();
-        let after_bytes = file_paths
-            .after
-            .as_deref()
-            .map(|p| read_jj_file(after_rev.as_deref(), p))
-            .unwrap_or_default();
+impl LineTotals {
+    fn of<'a>(files: impl IntoIterator<Item = &'a FileStat>) -> Self {
+        files.into_iter().fold(Self::default(), |total, file| Self {
+            files: total.files + 1,
+            hunks: total.hunks + file.hunks.len(),
+            added: total.added + file.added,
+            removed: total.removed + file.removed,
+        })
+    }
+}
 
-        let is_binary = is_binary_data(&before_bytes) || is_binary_data(&after_bytes);
-        if is_binary && options.binary == BinaryMode::Skip {
-            continue;
-        }
+#[derive(Debug, Serialize)]
+struct SpecTemplateOutput {
+    files: HashMap<String, SpecTemplateEntry>,
+    default: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    fuzzy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expect: Option<Expectation>,
+}
 
-        let should_diff = !(is_binary && options.binary == BinaryMode::Mark);
-        let (before_text, before_truncated) = if should_diff {
-            truncate_text(
-                &String::from_utf8_lossy(&before_bytes),
-                options.max_bytes,
-                options.max_lines,
-            )
-        } else {
-            (String::new(), false)
-        };
-        let (after_text, after_truncated) = if should_diff {
-            truncate_text(
-                &String::from_utf8_lossy(&after_bytes),
-                options.max_bytes,
-                options.max_lines,
-            )
-        } else {
-            (String::new(), false)
-        };
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum SpecTemplateEntry {
+    Ids { ids: Vec<String> },
+    Hunks { hunks: Vec<SpecTemplateHunk> },
+    Action { action: String },
+}
 
-        let mut hunks = if should_diff {
-            get_hunks(&before_text, &after_text)
-        } else {
-            Vec::new()
-        };
+/// A hunk of a fuzzy spec template: what relocation needs to find it again.
+#[derive(Debug, Serialize)]
+struct SpecTemplateHunk {
+    id: String,
+    removed: String,
+    added: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<HunkContext>,
+}
 
-        if let SpecDecision::KeepSelection(selection) = &decision {
-            hunks = filter_hunks(hunks, selection);
+impl From<Hunk> for SpecTemplateHunk {
+    fn from(hunk: Hunk) -> Self {
+        Self {
+            id: hunk.id,
+            removed: hunk.removed,
+            added: hunk.added,
+            context: hunk.context,
         }
+    }
+}
 
-        if hunks.is_empty() && !is_binary {
-            continue;
+/// List hunks in current working copy or a specific revision
+pub fn list<T>(session: &mut Session, options: T) -> Result<()>
+where
+    T: Into<ListOptions>,
+{
+    let options = options.into();
+    if let Some(template) = template_source(&options)? {
+        print!("{}", list_templated(session, &options, &template)?);
+        return Ok(());
+    }
+    if options.format == ListFormat::Ndjson {
+        return write_ndjson(session, &options, &mut std::io::stdout().lock());
+    }
+    let result = build_list(session, &options)?;
+    match options.format {
+        ListFormat::Json => println!("{}", serde_json::to_string_pretty(&result)?),
+        ListFormat::Yaml => println!("{}", serde_yaml::to_string(&result)?),
+        ListFormat::Text => {
+            let palette = Palette::new(options.color, std::io::stdout().is_terminal());
+            print!(
+                "{}",
+                render_text_result(&result, palette, text_columns(&options))
+            )
         }
+        ListFormat::Patch => print!("{}", render_patch_result(&result)),
+        ListFormat::Markdown => print!("{}", render_markdown_result(&result)),
+        ListFormat::Html => print!("{}", render_html_result(&result, options.rev.as_deref())),
+        ListFormat::Ndjson => unreachable!("ndjson is streamed above"),
+    }
+    Ok(())
+}
 
-        let rename = rename_info(&entry);
-        let truncated = before_truncated || after_truncated;
+/// List hunks as a JSON value; non-JSON formats are returned as a string.
+pub fn list_value(session: &mut Session, options: &ListOptions) -> Result<serde_json::Value> {
+    if let Some(template) = template_source(options)? {
+        let rendered = list_templated(session, options, &template)?;
+        return Ok(serde_json::Value::String(rendered));
+    }
+    if options.format == ListFormat::Ndjson {
+        let mut out = Vec::new();
+        write_ndjson(session, options, &mut out)?;
+        return Ok(serde_json::Value::String(String::from_utf8(out)?));
+    }
+    let result = build_list(session, options)?;
+    Ok(match options.format {
+        ListFormat::Json => serde_json::to_value(&result)?,
+        ListFormat::Yaml => serde_json::Value::String(serde_yaml::to_string(&result)?),
+        ListFormat::Text => {
+            let palette = Palette::new(options.color, false);
+            let columns = text_columns(options);
+            serde_json::Value::String(render_text_result(&result, palette, columns))
+        }
+        ListFormat::Patch => serde_json::Value::String(render_patch_result(&result)),
+        ListFormat::Markdown => serde_json::Value::String(render_markdown_result(&result)),
+        ListFormat::Html => {
+            serde_json::Value::String(render_html_result(&result, options.rev.as_deref()))
+        }
+        ListFormat::Ndjson => unreachable!("ndjson is streamed above"),
+    })
+}
 
-        files.push(FileEntry {
-            path,
-            status: entry.status.clone(),
-            rename,
-            hunks,
-            binary: if is_binary { Some(true) } else { None },
-            truncated: if truncated { Some(true) } else { None },
-        });
+fn build_list(session: &mut Session, options: &ListOptions) -> Result<ListResult> {
+    if options.mode == ListMode::SpecTemplate
+        && !matches!(options.format, ListFormat::Json | ListFormat::Yaml)
+    {
+        let format = options
+            .format
+            .to_possible_value()
+            .expect("no format is skipped");
+        anyhow::bail!(
+            "--spec-template does not support {} output (use json or yaml)",
+            format.get_name()
+        );
+    }
+    if options.side_by_side && options.format != ListFormat::Text {
+        anyhow::bail!("--side-by-side only applies to text output");
+    }
+    if options.fuzzy && options.mode != ListMode::SpecTemplate {
+        anyhow::bail!("--fuzzy only applies to --spec-template");
+    }
+    if options.fuzzy && (options.max_bytes.is_some() || options.max_lines.is_some()) {
+        anyhow::bail!("fuzzy spec templates need whole hunks (drop --max-bytes/--max-lines)");
+    }
+    if options.mode != ListMode::Full
+        && matches!(options.format, ListFormat::Patch | ListFormat::Html)
+    {
+        anyhow::bail!(
+            "patch and html output need full hunks (drop --files/--stat/--spec-template)"
+        );
+    }
+    if options.format == ListFormat::Patch
+        && (options.max_bytes.is_some() || options.max_lines.is_some())
+    {
+        anyhow::bail!("patch output needs whole hunks (drop --max-bytes/--max-lines)");
+    }
+    let mut pager = Pager::new(options)?;
+    if pager.is_some() && options.format == ListFormat::Html {
+        anyhow::bail!(
+            "html output cannot be paged (drop --limit/--offset/--cursor/--max-output-bytes)"
+        );
     }
 
-    match options.mode {
+    let spec = preview_spec(options)?;
+    let listed = collect_files(session, options, |path| {
+        !matches!(spec_decision(spec.as_ref(), path), SpecDecision::Skip)
+    })?;
+    // Spec templates pin the unfiltered hunks of each file, so keep them around.
+    let unfiltered = (options.mode == ListMode::SpecTemplate).then(|| listed.clone());
+    let filter = HunkFilter::new(options)?;
+    let mut files: Vec<FileEntry> = apply_spec_preview(listed, spec.as_ref())
+        .into_iter()
+        .filter_map(|file| filter.apply(file))
+        .collect();
+    if let Some(sort) = options.sort {
+        sort_files(&mut files, sort);
+    }
+    let mut files: Vec<FileEntry> = files
+        .into_iter()
+        .map(|file| truncate_payloads(file, options))
+        .collect();
+    let next_cursor = match pager.as_mut() {
+        Some(pager) => {
+            files = files
+                .into_iter()
+                .filter_map(|file| pager.page(file))
+                .collect();
+            pager.finish()?
+        }
+        None => None,
+    };
+
+    let mut result = match options.mode {
         ListMode::Full => {
-            let output = if options.group == ListGrouping::None {
-                ListOutput {
+            if options.group == ListGrouping::None {
+                ListResult::Full(ListOutput {
                     files: Some(files),
                     groups: None,
-                }
+                    next_cursor: None,
+                })
             } else {
                 let groups = group_files(files, options.group);
-                ListOutput {
+                ListResult::Full(ListOutput {
                     files: None,
                     groups: Some(groups),
-                }
-            };
-
-            match options.format {
-                ListFormat::Json => {
-                    println!("{}", serde_json::to_string_pretty(&output)?);
-                }
-                ListFormat::Yaml => {
-                    println!("{}", serde_yaml::to_string(&output)?);
-                }
-                ListFormat::Text => {
-                    print!("{}", render_text_output(&output));
-                }
-            }
-        }
-        ListMode::Files => {
-            let summary = build_summary_output(files, options.group);
-            match options.format {
-                ListFormat::Json => {
-                    println!("{}", serde_json::to_string_pretty(&summary)?);
-                }
-                ListFormat::Yaml => {
-                    println!("{}", serde_yaml::to_string(&summary)?);
-                }
-                ListFormat::Text => {
-                    print!("{}", render_text_summary_output(&summary));
-                }
+                    next_cursor: None,
+                })
             }
         }
+        ListMode::Files => ListResult::Files(build_summary_output(files, options.group)),
+        ListMode::Stat => ListResult::Stat(build_stat_output(files, options.group)),
         ListMode::SpecTemplate => {
-            if matches!(options.format, ListFormat::Text) {
-                anyhow::bail!("--spec-template does not support text output (use json or yaml)");
-            }
-            let template = build_spec_template(files);
-            match options.format {
-                ListFormat::Json => {
-                    println!("{}", serde_json::to_string_pretty(&template)?);
-                }
-                ListFormat::Yaml => {
-                    println!("{}", serde_yaml::to_string(&template)?);
-                }
-                ListFormat::Text => {}
+            let mut template = build_spec_template(files, options.fuzzy);
+            // Fuzzy templates are meant to outlive the revision they came from.
+            if !options.fuzzy {
+                template.expect = Some(build_expectation(
+                    session,
+                    options.rev.as_deref(),
+                    unfiltered.as_deref().unwrap_or_default(),
+                    &template.files,
+                )?);
             }
+            ListResult::SpecTemplate(template)
         }
-    }
-
-    Ok(())
-}
-
-const SUMMARY_TEMPLATE: &str = r#""{\"status\":" ++ self.status().escape_json() ++ ",\"path\":" ++ self.path().display().escape_json() ++ ",\"source\":" ++ self.source().path().display().escape_json() ++ ",\"target\":" ++ self.target().path().display().escape_json() ++ "}\n""#;
-
-struct FilePaths {
-    before: Option<String>,
-    after: Option<String>,
+    };
+    result.set_next_cursor(next_cursor);
+    Ok(result)
 }
 
-enum SpecDecision {
-    Skip,
-    KeepAll,
-    KeepSelection(HunkSelection),
+/// Position of the first file (and hunk) of the next page.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Cursor {
+    path: String,
+    hunk: usize,
 }
 
-fn resolve_optional_spec(spec: Option<&str>, spec_file: Option<&str>) -> Result<Option<String>> {
-    if spec.is_none() && spec_file.is_none() {
-        return Ok(None);
+impl Cursor {
+    /// An opaque token: hex-encoded JSON, safe to pass on a command line.
+    fn encode(&self) -> String {
+        hex_encode(&serde_json::to_vec(self).expect("cursors always serialize"))
     }
 
-    Ok(Some(resolve_spec_input(spec, spec_file)?))
-}
-
-fn resolve_revisions(revset: Option<&str>) -> (Option<String>, Option<String>) {
-    if let Some(rev) = revset {
-        (Some(format!("({})^", rev)), Some(rev.to_string()))
-    } else {
-        (Some("@-".to_string()), None)
+    fn decode(token: &str) -> Result<Self> {
+        let invalid = || {
+            anyhow::anyhow!("Invalid --cursor {token:?}; pass the next_cursor of a previous page")
+        };
+        if !token.len().is_multiple_of(2) || !token.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&token[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        serde_json::from_slice(&bytes).map_err(|_| invalid())
     }
 }
 
-fn read_diff_summary(revset: Option<&str>) -> Result<Vec<DiffSummaryEntry>> {
-    let mut diff_args = vec!["diff", "--template", SUMMARY_TEMPLATE];
-    if let Some(rev) = revset {
-        diff_args.push("-r");
-        diff_args.push(rev);
-    }
-
-    let output = Command::new("jj")
-        .args(&diff_args)
-        .output()
-        .context("Failed to run jj diff")?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("jj diff failed: {}", stderr.trim());
-    }
+/// Cuts one page out of the listed files, in diff-summary order.
+///
+/// Pages never split a hunk: a page ends before the file (or hunk) that
+/// would pass `limit` or `max_output_bytes`, and always holds at least one.
+struct Pager {
+    unit: PageUnit,
+    mode: ListMode,
+    resume: Option<Cursor>,
+    skip: usize,
+    limit: Option<usize>,
+    budget: Option<usize>,
+    taken: usize,
+    used: usize,
+    next: Option<Cursor>,
+}
 
-    let summary = String::from_utf8_lossy(&output.stdout);
-    let mut entries = Vec::new();
-    for (index, line) in summary.lines().enumerate() {
-        if line.trim().is_empty() {
-            continue;
+impl Pager {
+    /// A pager for `options`, or `None` when the listing is not paged.
+    fn new(options: &ListOptions) -> Result<Option<Self>> {
+        if options.limit.is_none()
+            && options.offset == 0
+            && options.cursor.is_none()
+            && options.max_output_bytes.is_none()
+        {
+            return Ok(None);
+        }
+        match options.mode {
+            ListMode::SpecTemplate => {
+                anyhow::bail!("--spec-template covers every file and cannot be paged")
+            }
+            ListMode::Files if options.page_by == PageUnit::Hunk => {
+                anyhow::bail!("--files pages by file (drop --page-by hunk)")
+            }
+            _ => {}
+        }
+        if options.limit == Some(0) {
+            anyhow::bail!("--limit must be at least 1");
         }
-        let entry: DiffSummaryEntry = serde_json::from_str(line)
-            .with_context(|| format!("Failed to parse diff summary line {}", index + 1))?;
-        entries.push(entry);
+        Ok(Some(Self {
+            unit: options.page_by,
+            mode: options.mode,
+            resume: options.cursor.as_deref().map(Cursor::decode).transpose()?,
+            skip: options.offset,
+            limit: options.limit,
+            budget: options.max_output_bytes,
+            taken: 0,
+            used: 0,
+            next: None,
+        }))
     }
 
-    Ok(entries)
+    /// The part of `file` on this page, if any.
+    fn page(&mut self, mut file: FileEntry) -> Option<FileEntry> {
+        if self.next.is_some() {
+            return None;
+        }
+        if let Some(cursor) = &self.resume {
+            if cursor.path != file.path {
+                return None;
+            }
+            let from = cursor.hunk;
+            self.resume = None;
+            file.hunks.retain(|hunk| hunk.index >= from);
+        }
+
+        if self.unit == PageUnit::File || file.hunks.is_empty() {
+            if self.skip > 0 {
+                self.skip -= 1;
+                return None;
+            }
+            let first = file.hunks.first().map_or(0, |hunk| hunk.index);
+            return self
+                .take(self.file_size(&file), &file.path, first)
+                .then_some(file);
+        }
+
+        let hunks = std::mem::take(&mut file.hunks);
+        let header = self.file_size(&file);
+        for hunk in hunks {
+            if self.skip > 0 {
+                self.skip -= 1;
+                continue;
+            }
+            let size = self.hunk_size(&hunk) + if file.hunks.is_empty() { header } else { 0 };
+            if !self.take(size, &file.path, hunk.index) {
+                break;
+            }
+            file.hunks.push(hunk);
+        }
+        (!file.hunks.is_empty()).then_some(file)
+    }
+
+    /// Add an item of `size` bytes to the page, or end the page before it.
+    fn take(&mut self, size: usize, path: &str, hunk: usize) -> bool {
+        let over_limit = self.limit.is_some_and(|limit| self.taken >= limit);
+        let over_budget =
+            self.taken > 0 && self.budget.is_some_and(|budget| self.used + size > budget);
+        if over_limit || over_budget {
+            self.next = Some(Cursor {
+                path: path.to_string(),
+                hunk,
+            });
+            return false;
+        }
+        self.taken += 1;
+        self.used += size;
+        true
+    }
+
+    /// The token of the next page, or `None` after the last page.
+    fn finish(&self) -> Result<Option<String>> {
+        if let Some(cursor) = &self.resume {
+            anyhow::bail!(
+                "--cursor points at {}, which is no longer listed; list again from the start",
+                cursor.path
+            );
+        }
+        Ok(self.next.as_ref().map(Cursor::encode))
+    }
+
+    /// Bytes `file` adds to the JSON output, as this mode serializes it.
+    fn file_size(&self, file: &FileEntry) -> usize {
+        let json = match self.mode {
+            ListMode::Files => serde_json::to_string(&FileSummary::from(file.clone())),
+            ListMode::Stat => serde_json::to_string(&FileStat::from(file.clone())),
+            ListMode::Full | ListMode::SpecTemplate => serde_json::to_string(file),
+        };
+        json.map_or(0, |json| json.len())
+    }
+
+    fn hunk_size(&self, hunk: &Hunk) -> usize {
+        let json = match self.mode {
+            ListMode::Stat => serde_json::to_string(&HunkStat::from(hunk.clone())),
+            _ => serde_json::to_string(hunk),
+        };
+        json.map_or(0, |json| json.len())
+    }
+}
+
+/// The `template` text, or the contents of `template_file`.
+fn template_source(options: &ListOptions) -> Result<Option<String>> {
+    match (&options.template, &options.template_file) {
+        (Some(_), Some(_)) => anyhow::bail!("Use either --template or --template-file, not both"),
+        (Some(template), None) => Ok(Some(template.clone())),
+        (None, Some(path)) => fs::read_to_string(path)
+            .with_context(|| format!("Failed to read template file {}", path))
+            .map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Render every listed file, or every hunk, through a `--template`.
+fn list_templated(session: &mut Session, options: &ListOptions, source: &str) -> Result<String> {
+    let template = Template::parse(source)?;
+    if options.cursor.is_some() || options.max_output_bytes.is_some() || options.limit.is_some() {
+        anyhow::bail!(
+            "--template output has no place for a cursor; page with --format json instead"
+        );
+    }
+    match options.mode {
+        ListMode::SpecTemplate => anyhow::bail!("--template cannot render a --spec-template"),
+        ListMode::Stat => anyhow::bail!(
+            "--template cannot render --stat (use hunk.before.lines and hunk.after.lines)"
+        ),
+        ListMode::Full | ListMode::Files => {}
+    }
+    if options.mode == ListMode::Files && template.uses_hunks() {
+        anyhow::bail!("--files has no hunks; drop hunk.* fields from the template");
+    }
+
+    let mut out = String::new();
+    match build_list(session, options)? {
+        ListResult::Full(output) => {
+            let groups: Vec<(Option<&str>, &[FileEntry])> = match (&output.groups, &output.files) {
+                (Some(groups), _) => groups
+                    .iter()
+                    .map(|group| (Some(group.name.as_str()), group.files.as_slice()))
+                    .collect(),
+                (None, Some(files)) => vec![(None, files.as_slice())],
+                (None, None) => Vec::new(),
+            };
+            for (group, files) in groups {
+                for file in files {
+                    let context = TemplateFile {
+                        path: &file.path,
+                        status: &file.status,
+                        group,
+                        renamed_from: file.rename.as_ref().map(|rename| rename.from.as_str()),
+                        hunk_count: file.hunks.len(),
+                        binary: file.binary == Some(true),
+                        truncated: file.truncated == Some(true),
+                    };
+                    if template.uses_hunks() {
+                        for hunk in &file.hunks {
+                            out.push_str(&template.render(&context, Some(hunk)));
+                        }
+                    } else {
+                        out.push_str(&template.render(&context, None));
+                    }
+                }
+            }
+        }
+        ListResult::Files(summary) => {
+            let groups: Vec<(Option<&str>, &[FileSummary])> =
+                match (&summary.groups, &summary.files) {
+                    (Some(groups), _) => groups
+                        .iter()
+                        .map(|group| (Some(group.name.as_str()), group.files.as_slice()))
+                        .collect(),
+                    (None, Some(files)) => vec![(None, files.as_slice())],
+                    (None, None) => Vec::new(),
+                };
+            for (group, files) in groups {
+                for file in files {
+                    out.push_str(&template.render(
+                        &TemplateFile {
+                            path: &file.path,
+                            status: &file.status,
+                            group,
+                            renamed_from: file.rename.as_ref().map(|rename| rename.from.as_str()),
+                            hunk_count: file.hunk_count,
+                            binary: file.binary == Some(true),
+                            truncated: file.truncated == Some(true),
+                        },
+                        None,
+                    ));
+                }
+            }
+        }
+        ListResult::Stat(_) | ListResult::SpecTemplate(_) => {}
+    }
+    Ok(out)
+}
+
+/// Write one `file` record per listed file as soon as it is diffed, then a
+/// `summary` record with the totals.
+fn write_ndjson(session: &mut Session, options: &ListOptions, out: &mut dyn Write) -> Result<()> {
+    if options.mode == ListMode::SpecTemplate {
+        anyhow::bail!("--spec-template does not support ndjson output (use json or yaml)");
+    }
+
+    if options.sort.is_some() {
+        anyhow::bail!("ndjson streams files in diff order and cannot be sorted (drop --sort)");
+    }
+
+    let spec = preview_spec(options)?;
+    let filter = HunkFilter::new(options)?;
+    let mut pager = Pager::new(options)?;
+    let group = |file: &FileEntry| {
+        (options.group != ListGrouping::None)
+            .then(|| group_key(&file.path, &file.status, options.group))
+    };
+    let (mut files, mut hunks, mut added, mut removed) = (0, 0, 0, 0);
+    let mut written = Ok(());
+
+    for_each_file(
+        session,
+        options,
+        |path| !matches!(spec_decision(spec.as_ref(), path), SpecDecision::Skip),
+        |file| {
+            if written.is_err() {
+                return;
+            }
+            let Some(file) = preview_file(file, spec.as_ref()).and_then(|file| filter.apply(file))
+            else {
+                return;
+            };
+            let file = truncate_payloads(file, options);
+            let file = match pager.as_mut() {
+                Some(pager) => match pager.page(file) {
+                    Some(file) => file,
+                    None => return,
+                },
+                None => file,
+            };
+            files += 1;
+            hunks += file.hunks.len();
+            let group = group(&file);
+            written = match options.mode {
+                ListMode::Files => write_ndjson_record(
+                    out,
+                    &NdjsonRecord::File {
+                        group,
+                        file: FileSummary::from(file),
+                    },
+                ),
+                ListMode::Stat => {
+                    let file = FileStat::from(file);
+                    added += file.added;
+                    removed += file.removed;
+                    write_ndjson_record(out, &NdjsonRecord::File { group, file })
+                }
+                _ => write_ndjson_record(out, &NdjsonRecord::File { group, file }),
+            };
+        },
+    )?;
+    written?;
+
+    let next_cursor = match &pager {
+        Some(pager) => pager.finish()?,
+        None => None,
+    };
+    let stat = options.mode == ListMode::Stat;
+    write_ndjson_record(
+        out,
+        &NdjsonRecord::<FileEntry>::Summary {
+            files,
+            hunks,
+            added: stat.then_some(added),
+            removed: stat.then_some(removed),
+            next_cursor,
+        },
+    )
+}
+
+fn write_ndjson_record<T: Serialize>(out: &mut dyn Write, record: &NdjsonRecord<T>) -> Result<()> {
+    serde_json::to_writer(&mut *out, record)?;
+    writeln!(out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Render `result` as text; `columns` switches hunks to side-by-side.
+fn render_text_result(result: &ListResult, palette: Palette, columns: Option<usize>) -> String {
+    let mut text = match result {
+        ListResult::Full(output) => render_text_output(output, palette, columns),
+        ListResult::Files(summary) => render_text_summary_output(summary, palette),
+        ListResult::Stat(stat) => render_text_stat_output(stat, palette),
+        ListResult::SpecTemplate(_) => String::new(),
+    };
+    if let Some(cursor) = result.next_cursor() {
+        let more = format!("More to list: continue with --cursor {cursor}");
+        text.push_str(&palette.paint(Palette::DIM, &more));
+        text.push('\n');
+    }
+    text
+}
+
+/// Width of side-by-side text output, or `None` for the unified layout.
+fn text_columns(options: &ListOptions) -> Option<usize> {
+    options.side_by_side.then(|| {
+        options
+            .width
+            .or_else(|| std::env::var("COLUMNS").ok()?.parse().ok())
+            .unwrap_or(DEFAULT_COLUMNS)
+    })
+}
+
+/// Listed files in output order; groups are written one after another.
+fn listed_files(result: &ListResult) -> Vec<&FileEntry> {
+    let ListResult::Full(output) = result else {
+        return Vec::new();
+    };
+    match (&output.groups, &output.files) {
+        (Some(groups), _) => groups.iter().flat_map(|group| &group.files).collect(),
+        (None, Some(files)) => files.iter().collect(),
+        (None, None) => Vec::new(),
+    }
+}
+
+/// Render listed files as a unified diff.
+fn render_patch_result(result: &ListResult) -> String {
+    let files: Vec<PatchFile> = listed_files(result)
+        .into_iter()
+        .map(|file| {
+            let (old_path, new_path) = match &file.rename {
+                Some(rename) => (rename.from.as_str(), rename.to.as_str()),
+                None => (file.path.as_str(), file.path.as_str()),
+            };
+            PatchFile {
+                status: &file.status,
+                old_path,
+                new_path,
+                hunks: &file.hunks,
+                binary: file.binary == Some(true),
+            }
+        })
+        .collect();
+    let patch = patch::render(&files);
+    // Text before the first `diff --git` is ignored by patch tools.
+    match result.next_cursor() {
+        Some(cursor) => format!("More to list: continue with --cursor {cursor}\n\n{patch}"),
+        None => patch,
+    }
+}
+
+/// Render listed files as a standalone HTML review page.
+fn render_html_result(result: &ListResult, rev: Option<&str>) -> String {
+    let files: Vec<ReportFile> = listed_files(result)
+        .into_iter()
+        .map(|file| ReportFile {
+            path: &file.path,
+            status: &file.status,
+            renamed_from: file.rename.as_ref().map(|rename| rename.from.as_str()),
+            hunks: &file.hunks,
+            binary: file.binary == Some(true),
+        })
+        .collect();
+    html::render(&format!("jj-hunk review of {}", rev.unwrap_or("@")), &files)
+}
+
+/// Read and diff every changed file that passes the path filters.
+///
+/// Hunks are returned unfiltered; spec previews are applied separately so
+/// callers can still see the full hunk list of each file.
+fn collect_files<F>(
+    session: &mut Session,
+    options: &ListOptions,
+    keep_path: F,
+) -> Result<Vec<FileEntry>>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut files = Vec::new();
+    for_each_file(session, options, keep_path, |file| files.push(file))?;
+    Ok(files)
+}
+
+/// Like `collect_files`, but hands each file to `emit` in diff-summary order
+/// as soon as it and all files before it are diffed.
+fn for_each_file<F, E>(
+    session: &mut Session,
+    options: &ListOptions,
+    keep_path: F,
+    mut emit: E,
+) -> Result<()>
+where
+    F: Fn(&str) -> bool,
+    E: FnMut(FileEntry),
+{
+    let include = normalize_patterns(&options.include);
+    let exclude = normalize_patterns(&options.exclude);
+
+    let summary_entries = session
+        .backend
+        .diff_summary(options.rev.as_deref(), &options.paths)?;
+    let (before_rev, after_rev) = resolve_revisions(options.rev.as_deref());
+    let commits = session.resolve_commits(options.rev.as_deref());
+    if let Some((before, after)) = &commits {
+        session.retain_commits(before, after);
+    }
+
+    // Each listed file is either cached already or diffed below.
+    let mut slots: Vec<Result<Option<FileEntry>, usize>> = Vec::new();
+    let mut pending = Vec::new();
+
+    for entry in summary_entries {
+        let path = primary_path(&entry);
+        if path.is_empty() {
+            continue;
+        }
+
+        if !should_include_entry(&entry, &include, &exclude) {
+            continue;
+        }
+
+        if !keep_path(&path) {
+            continue;
+        }
+
+        let cache_key = commits.as_ref().map(|(before, after)| EntryCacheKey {
+            before: before.clone(),
+            after: after.clone(),
+            path: path.clone(),
+            binary: options.binary,
+        });
+        if let Some(cached) = cache_key.as_ref().and_then(|key| session.entries.get(key)) {
+            slots.push(Ok(cached.clone()));
+            continue;
+        }
+
+        slots.push(Err(pending.len()));
+        pending.push((entry, path, cache_key));
+    }
+
+    // Materialize both trees with one jj process instead of two per file.
+    let snapshot = if pending.len() > 1 {
+        session
+            .backend
+            .materialize_diff(options.rev.as_deref(), &options.paths)
+            .ok()
+    } else {
+        None
+    };
+    let before_commit = commits.as_ref().map(|(before, _)| before.as_str());
+    let after_commit = commits.as_ref().map(|(_, after)| after.as_str());
+    let before_rev = before_commit.or(before_rev.as_deref());
+    let after_rev = after_commit.or(after_rev.as_deref());
+
+    let cache = session.hunk_cache();
+    let Session {
+        backend,
+        contents,
+        entries,
+        ..
+    } = session;
+    let backend = &**backend;
+    let read = |rev: Option<&str>, path: &str, snapshotted: Option<Vec<u8>>| {
+        if let Some(bytes) = snapshotted {
+            return bytes;
+        }
+        let key = rev.map(|rev| (rev.to_string(), path.to_string()));
+        match key.as_ref().and_then(|key| contents.get(key)) {
+            Some(bytes) => bytes.clone(),
+            None => backend.read_file(rev, path),
+        }
+    };
+
+    let mut slots = slots.into_iter();
+    let mut read_contents = Vec::new();
+
+    emit_cached(&mut slots, &mut emit);
+    parallel_for_each(
+        &pending,
+        |(entry, path, _)| {
+            let file_paths = file_paths_for_entry(entry, path);
+            let before_bytes = file_paths
+                .before
+                .as_deref()
+                .map(|p| {
+                    read(
+                        before_rev,
+                        p,
+                        snapshot.as_ref().and_then(|s| s.read_before(p)),
+                    )
+                })
+                .unwrap_or_default();
+            let after_bytes = file_paths
+                .after
+                .as_deref()
+                .map(|p| {
+                    read(
+                        after_rev,
+                        p,
+                        snapshot.as_ref().and_then(|s| s.read_after(p)),
+                    )
+                })
+                .unwrap_or_default();
+            let file = diff_entry(entry, path, options, &cache, &before_bytes, &after_bytes);
+            (file, file_paths, before_bytes, after_bytes)
+        },
+        |index, (file, file_paths, before_bytes, after_bytes)| {
+            if let Some(key) = &pending[index].2 {
+                if let Some(path) = file_paths.before {
+                    read_contents.push(((key.before.clone(), path), before_bytes));
+                }
+                if let Some(path) = file_paths.after {
+                    read_contents.push(((key.after.clone(), path), after_bytes));
+                }
+                entries.insert(key.clone(), file.clone());
+            }
+            // Pending files arrive in order, so this is the next slot.
+            slots.next();
+            if let Some(file) = file {
+                emit(file);
+            }
+            emit_cached(&mut slots, &mut emit);
+        },
+    );
+
+    contents.extend(read_contents);
+    Ok(())
+}
+
+/// Emit cached files up to the next one still being diffed.
+fn emit_cached<E: FnMut(FileEntry)>(
+    slots: &mut std::vec::IntoIter<Result<Option<FileEntry>, usize>>,
+    emit: &mut E,
+) {
+    while let Some(Ok(_)) = slots.as_slice().first() {
+        if let Some(Ok(Some(file))) = slots.next() {
+            emit(file);
+        }
+    }
+}
+
+/// Diff one changed file, or `None` if there is nothing to list.
+fn diff_entry(
+    entry: &DiffSummaryEntry,
+    path: &str,
+    options: &ListOptions,
+    cache: &HunkCache,
+    before_bytes: &[u8],
+    after_bytes: &[u8],
+) -> Option<FileEntry> {
+    let is_binary = is_binary_data(before_bytes) || is_binary_data(after_bytes);
+    if is_binary && options.binary == BinaryMode::Skip {
+        return None;
+    }
+
+    let should_diff = !(is_binary && options.binary == BinaryMode::Mark);
+    let hunks = if should_diff {
+        cache
+            .file_diff(
+                &String::from_utf8_lossy(before_bytes),
+                &String::from_utf8_lossy(after_bytes),
+            )
+            .into_hunks()
+    } else {
+        Vec::new()
+    };
+
+    if hunks.is_empty() && !is_binary {
+        return None;
+    }
+
+    Some(FileEntry {
+        path: path.to_string(),
+        status: entry.status.clone(),
+        rename: rename_info(entry),
+        hunks,
+        binary: if is_binary { Some(true) } else { None },
+        truncated: None,
+    })
+}
+
+/// Cut the removed/added text of each hunk to `--max-bytes`/`--max-lines`.
+///
+/// Hunks are always computed from whole files, so ids and ranges stay
+/// usable in specs; only the displayed text is shortened.
+fn truncate_payloads(mut file: FileEntry, options: &ListOptions) -> FileEntry {
+    if options.max_bytes.is_none() && options.max_lines.is_none() {
+        return file;
+    }
+    for hunk in &mut file.hunks {
+        let (removed, removed_truncated) =
+            truncate_text(&hunk.removed, options.max_bytes, options.max_lines);
+        let (added, added_truncated) =
+            truncate_text(&hunk.added, options.max_bytes, options.max_lines);
+        if removed_truncated || added_truncated {
+            hunk.removed = removed;
+            hunk.added = added;
+            hunk.truncated = Some(true);
+            file.truncated = Some(true);
+        }
+    }
+    file
+}
+
+/// Map `f` over `items` on up to `MAX_JOBS` threads, keeping input order.
+fn parallel_map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let mut results = Vec::with_capacity(items.len());
+    parallel_for_each(items, f, |_, result| results.push(result));
+    results
+}
+
+/// Run `f` over `items` on up to `MAX_JOBS` threads, passing each result to
+/// `consume` on the calling thread in input order as soon as it is ready.
+fn parallel_for_each<T, R, F, C>(items: &[T], f: F, mut consume: C)
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+    C: FnMut(usize, R),
+{
+    let jobs = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(MAX_JOBS)
+        .min(items.len());
+    if jobs <= 1 {
+        for (index, item) in items.iter().enumerate() {
+            consume(index, f(item));
+        }
+        return;
+    }
+
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            let sender = sender.clone();
+            let (next, f) = (&next, &f);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(index) else {
+                    break;
+                };
+                if sender.send((index, f(item))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut ready = BTreeMap::new();
+        let mut next_out = 0;
+        for (index, result) in receiver {
+            ready.insert(index, result);
+            while let Some(result) = ready.remove(&next_out) {
+                consume(next_out, result);
+                next_out += 1;
+            }
+        }
+    });
+}
+
+/// The spec given to `list` for previewing, if any.
+fn preview_spec(options: &ListOptions) -> Result<Option<Spec>> {
+    resolve_optional_spec(options.spec.as_deref(), options.spec_file.as_deref())?
+        .map(|content| Spec::from_str(&content))
+        .transpose()
+}
+
+/// The `--type`, `--min-lines`, `--max-lines-changed` and `--grep` filters.
+struct HunkFilter {
+    types: Vec<HunkKind>,
+    min_lines: Option<usize>,
+    max_lines: Option<usize>,
+    grep: Option<Regex>,
+}
+
+impl HunkFilter {
+    fn new(options: &ListOptions) -> Result<Self> {
+        let grep = options
+            .grep
+            .as_deref()
+            .map(|pattern| {
+                Regex::new(pattern).with_context(|| format!("Invalid --grep regex {pattern:?}"))
+            })
+            .transpose()?;
+        Ok(Self {
+            types: options.types.clone(),
+            min_lines: options.min_lines,
+            max_lines: options.max_lines_changed,
+            grep,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.types.is_empty()
+            && self.min_lines.is_none()
+            && self.max_lines.is_none()
+            && self.grep.is_none()
+    }
+
+    fn keep(&self, hunk: &Hunk) -> bool {
+        let changed = hunk.before_range.length + hunk.after_range.length;
+        (self.types.is_empty() || self.types.iter().any(|kind| kind.matches(hunk)))
+            && self.min_lines.is_none_or(|min| changed >= min)
+            && self.max_lines.is_none_or(|max| changed <= max)
+            && self
+                .grep
+                .as_ref()
+                .is_none_or(|grep| grep.is_match(&hunk.removed) || grep.is_match(&hunk.added))
+    }
+
+    /// Keep only matching hunks, or `None` if none match.
+    ///
+    /// Hunks keep their original index, as with spec previews.
+    fn apply(&self, mut file: FileEntry) -> Option<FileEntry> {
+        if self.is_empty() {
+            return Some(file);
+        }
+        file.hunks.retain(|hunk| self.keep(hunk));
+        (!file.hunks.is_empty()).then_some(file)
+    }
+}
+
+/// Order files for `--sort`; ties keep path order.
+fn sort_files(files: &mut [FileEntry], sort: ListSort) {
+    let changed = |file: &FileEntry| -> usize {
+        file.hunks
+            .iter()
+            .map(|hunk| hunk.before_range.length + hunk.after_range.length)
+            .sum()
+    };
+    files.sort_by(|a, b| {
+        let order = match sort {
+            ListSort::Path => std::cmp::Ordering::Equal,
+            ListSort::Size => changed(b).cmp(&changed(a)),
+            ListSort::Hunks => b.hunks.len().cmp(&a.hunks.len()),
+            ListSort::Status => a.status.cmp(&b.status),
+        };
+        order.then_with(|| a.path.cmp(&b.path))
+    });
+}
+
+fn apply_spec_preview(files: Vec<FileEntry>, spec: Option<&Spec>) -> Vec<FileEntry> {
+    files
+        .into_iter()
+        .filter_map(|file| preview_file(file, spec))
+        .collect()
+}
+
+/// Keep only the hunks `spec` would select, or `None` if nothing is left.
+fn preview_file(mut file: FileEntry, spec: Option<&Spec>) -> Option<FileEntry> {
+    match spec_decision(spec, &file.path) {
+        SpecDecision::Skip => None,
+        SpecDecision::KeepAll => Some(file),
+        SpecDecision::KeepSelection(mut selection) => {
+            if spec.is_some_and(|spec| spec.fuzzy) {
+                for relocation in selection.relocate(&file.hunks) {
+                    report_relocation(&file.path, &relocation);
+                }
+            }
+            file.hunks = filter_hunks(file.hunks, &selection);
+            if file.hunks.is_empty() && file.binary != Some(true) {
+                None
+            } else {
+                Some(file)
+            }
+        }
+    }
+}
+
+fn build_expectation(
+    session: &Session,
+    rev: Option<&str>,
+    files: &[FileEntry],
+    template_files: &HashMap<String, SpecTemplateEntry>,
+) -> Result<Expectation> {
+    let target = rev.unwrap_or("@");
+    let files: Vec<&FileEntry> = files
+        .iter()
+        .filter(|file| template_files.contains_key(&file.path))
+        .collect();
+    let hunks = digest_text_hunks(files.iter().copied());
+    let mut paths: Vec<String> = files.iter().map(|file| file.path.clone()).collect();
+    paths.sort();
+
+    Ok(Expectation {
+        commit_id: Some(session.backend.commit_id(target)?),
+        tree_id: Some(session.backend.tree_id(target)?),
+        operation_id: Some(session.backend.operation_id()?),
+        hunks: Some(hunks),
+        paths: Some(paths),
+    })
+}
+
+/// Refuse to run when the target revision no longer matches `spec.expect`.
+fn verify_expectation(session: &mut Session, spec: &Spec, rev: Option<&str>) -> Result<()> {
+    let Some(expect) = &spec.expect else {
+        return Ok(());
+    };
+    let target = rev.unwrap_or("@");
+
+    if let Some(expected) = &expect.commit_id {
+        let actual = session.backend.commit_id(target)?;
+        if !id_matches(&actual, expected) {
+            anyhow::bail!(
+                "Spec expects {target} to be commit {expected}, but it is now {actual}; \
+                 re-list the revision and update the spec"
+            );
+        }
+    }
+
+    if let Some(expected) = &expect.tree_id {
+        let actual = session.backend.tree_id(target)?;
+        if !id_matches(&actual, expected) {
+            anyhow::bail!(
+                "Spec expects {target} to have tree {expected}, but it is now {actual}; \
+                 re-list the revision and update the spec"
+            );
+        }
+    }
+
+    if let Some(expected) = &expect.operation_id {
+        let actual = session.backend.operation_id()?;
+        if !id_matches(&actual, expected) {
+            anyhow::bail!(
+                "Spec expects operation {expected}, but the repo is now at {actual}; \
+                 re-list the revision and update the spec"
+            );
+        }
+    }
+
+    if let Some(expected) = &expect.hunks {
+        // `expect.paths` already holds whatever `list --paths` narrowed to.
+        let options = ListOptions::from(rev);
+        let files = match &expect.paths {
+            Some(paths) => collect_files(session, &options, |path| {
+                paths.iter().any(|listed| listed == path)
+            })?,
+            None => collect_files(session, &options, |path| spec.files.contains_key(path))?,
+        };
+        let actual = digest_text_hunks(&files);
+        if actual != *expected {
+            anyhow::bail!(
+                "Hunks of {target} no longer match the spec (expected {expected}, found {actual}); \
+                 re-list the revision and update the spec"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Digest the hunk ids of `files`, leaving out binary hunks so the digest
+/// doesn't depend on the `--binary` mode the files were listed with.
+fn digest_text_hunks<'a>(files: impl IntoIterator<Item = &'a FileEntry>) -> String {
+    digest_hunk_ids(files.into_iter().map(|file| {
+        let hunks: &[Hunk] = if file.binary == Some(true) {
+            &[]
+        } else {
+            &file.hunks
+        };
+        (file.path.as_str(), hunks)
+    }))
+}
+
+fn id_matches(actual: &str, expected: &str) -> bool {
+    let expected = expected.trim();
+    !expected.is_empty() && actual.starts_with(&expected.to_lowercase())
+}
+
+/// How a spec lines up with the current diff of a revision.
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expect_error: Option<String>,
+    files: Vec<FileCheck>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.ok
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FileCheck {
+    path: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    missing: bool,
+    selected: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unknown_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    invalid_indices: Vec<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    relocated: Vec<RelocatedId>,
+}
+
+#[derive(Debug, Serialize)]
+struct RelocatedId {
+    from: String,
+    to: String,
+}
+
+/// Check a spec against a revision without changing anything
+pub fn check(
+    session: &mut Session,
+    spec: Option<&str>,
+    spec_file: Option<&str>,
+    rev: Option<&str>,
+) -> Result<()> {
+    let report = check_report(session, spec, spec_file, rev)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.is_ok() {
+        anyhow::bail!("Spec does not match the current diff");
+    }
+    Ok(())
+}
+
+pub fn check_report(
+    session: &mut Session,
+    spec: Option<&str>,
+    spec_file: Option<&str>,
+    rev: Option<&str>,
+) -> Result<CheckReport> {
+    let spec = Spec::from_str(&resolve_spec_input(spec, spec_file)?)?;
+    let expect_error = verify_expectation(session, &spec, rev)
+        .err()
+        .map(|err| err.to_string());
+
+    let options = ListOptions::from(rev);
+    let listed = collect_files(session, &options, |path| spec.files.contains_key(path))?;
+    let listed: HashMap<&str, &FileEntry> = listed
+        .iter()
+        .map(|file| (file.path.as_str(), file))
+        .collect();
+
+    let mut paths: Vec<&String> = spec.files.keys().collect();
+    paths.sort();
+
+    let mut files = Vec::new();
+    for path in paths {
+        let Some(file) = listed.get(path.as_str()) else {
+            files.push(FileCheck {
+                path: path.clone(),
+                missing: true,
+                selected: 0,
+                unknown_ids: Vec::new(),
+                invalid_indices: Vec::new(),
+                relocated: Vec::new(),
+            });
+            continue;
+        };
+
+        let mut check = FileCheck {
+            path: path.clone(),
+            missing: false,
+            selected: 0,
+            unknown_ids: Vec::new(),
+            invalid_indices: Vec::new(),
+            relocated: Vec::new(),
+        };
+
+        match &spec.files[path] {
+            FileSpec::Action {
+                action: Action::Keep,
+            } => check.selected = file.hunks.len(),
+            FileSpec::Action {
+                action: Action::Reset,
+            } => {}
+            FileSpec::Selection(selection) => {
+                let mut selection = selection.to_selection();
+                if spec.fuzzy {
+                    for relocation in selection.relocate(&file.hunks) {
+                        if let Relocation::Moved { from, to, .. } = relocation {
+                            check.relocated.push(RelocatedId { from, to });
+                        }
+                    }
+                }
+
+                let ids: HashSet<&str> = file.hunks.iter().map(|hunk| hunk.id.as_str()).collect();
+                check.unknown_ids = selection
+                    .ids
+                    .iter()
+                    .filter(|id| !ids.contains(id.as_str()))
+                    .cloned()
+                    .collect();
+                check.unknown_ids.sort();
+                check.invalid_indices = selection
+                    .indices
+                    .iter()
+                    .copied()
+                    .filter(|index| *index >= file.hunks.len())
+                    .collect();
+                check.invalid_indices.sort_unstable();
+                check.selected = file
+                    .hunks
+                    .iter()
+                    .filter(|hunk| selection.matches(hunk.index, &hunk.id))
+                    .count();
+            }
+        }
+
+        files.push(check);
+    }
+
+    let ok = expect_error.is_none()
+        && files.iter().all(|file| {
+            !file.missing && file.unknown_ids.is_empty() && file.invalid_indices.is_empty()
+        });
+
+    Ok(CheckReport {
+        ok,
+        expect_error,
+        files,
+    })
+}
+
+struct FilePaths {
+    before: Option<String>,
+    after: Option<String>,
+}
+
+enum SpecDecision {
+    Skip,
+    KeepAll,
+    KeepSelection(HunkSelection),
+}
+
+fn resolve_optional_spec(spec: Option<&str>, spec_file: Option<&str>) -> Result<Option<String>> {
+    if spec.is_none() && spec_file.is_none() {
+        return Ok(None);
+    }
+
+    Ok(Some(resolve_spec_input(spec, spec_file)?))
+}
+
+fn resolve_revisions(revset: Option<&str>) -> (Option<String>, Option<String>) {
+    if let Some(rev) = revset {
+        (Some(format!("({})^", rev)), Some(rev.to_string()))
+    } else {
+        (Some("@-".to_string()), None)
+    }
 }
 
 fn primary_path(entry: &DiffSummaryEntry) -> String {
@@ -473,19 +1908,30 @@ fn file_paths_for_entry(entry: &DiffSummaryEntry, path: &str) -> FilePaths {
     }
 }
 
-fn read_jj_file(rev: Option<&str>, path: &str) -> Vec<u8> {
-    let mut args = vec!["file", "show"];
-    if let Some(rev) = rev {
-        args.push("-r");
-        args.push(rev);
-    }
-    args.push(path);
+/// Copy the directories jj materialized for a diff tool into `dest`.
+pub fn snapshot(left: &str, right: &str, dest: &str) -> Result<()> {
+    let dest = Path::new(dest);
+    copy_tree(Path::new(left), &dest.join("left"))?;
+    copy_tree(Path::new(right), &dest.join("right"))
+}
 
-    Command::new("jj")
-        .args(&args)
-        .output()
-        .map(|o| o.stdout)
-        .unwrap_or_default()
+fn copy_tree(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in WalkDir::new(from).into_iter().filter_map(|e| e.ok()) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let Ok(rel) = entry.path().strip_prefix(from) else {
+            continue;
+        };
+        let target = to.join(rel);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(entry.path(), &target)
+            .with_context(|| format!("Failed to copy {}", entry.path().display()))?;
+    }
+    Ok(())
 }
 
 fn is_binary_data(bytes: &[u8]) -> bool {
@@ -512,14 +1958,12 @@ fn truncate_text(
             result.clear();
         } else {
             let mut limited = String::new();
-            let mut count = 0usize;
-            for line in result.split_inclusive('\n') {
+            for (count, line) in result.split_inclusive('\n').enumerate() {
                 if count >= max_lines {
                     truncated = true;
                     break;
                 }
                 limited.push_str(line);
-                count += 1;
             }
             if truncated {
                 result = limited;
@@ -601,7 +2045,7 @@ fn should_include_entry(entry: &DiffSummaryEntry, include: &[String], exclude: &
     true
 }
 
-fn entry_paths<'a>(entry: &'a DiffSummaryEntry) -> Vec<&'a str> {
+fn entry_paths(entry: &DiffSummaryEntry) -> Vec<&str> {
     let mut paths = Vec::new();
     if !entry.path.is_empty() {
         paths.push(entry.path.as_str());
@@ -619,7 +2063,7 @@ fn matches_any(patterns: &[String], path: &str) -> bool {
     patterns.iter().any(|pattern| glob_match(pattern, path))
 }
 
-fn glob_match(pattern: &str, path: &str) -> bool {
+pub(crate) fn glob_match(pattern: &str, path: &str) -> bool {
     let pattern = pattern.trim_start_matches("./");
     let path = path.trim_start_matches("./");
 
@@ -693,12 +2137,7 @@ fn group_files(files: Vec<FileEntry>, grouping: ListGrouping) -> Vec<ListGroup>
     let mut index: HashMap<String, usize> = HashMap::new();
 
     for file in files {
-        let key = match grouping {
-            ListGrouping::Directory => directory_group(&file.path),
-            ListGrouping::Extension => extension_group(&file.path),
-            ListGrouping::Status => file.status.clone(),
-            ListGrouping::None => String::new(),
-        };
+        let key = group_key(&file.path, &file.status, grouping);
 
         if let Some(position) = index.get(&key).copied() {
             groups[position].files.push(file);
@@ -715,28 +2154,54 @@ fn group_files(files: Vec<FileEntry>, grouping: ListGrouping) -> Vec<ListGroup>
 }
 
 fn build_summary_output(files: Vec<FileEntry>, grouping: ListGrouping) -> ListSummaryOutput {
-    let summaries: Vec<FileSummary> = files
-        .into_iter()
-        .map(|file| FileSummary {
-            path: file.path,
-            status: file.status,
-            rename: file.rename,
-            hunk_count: file.hunks.len(),
-            binary: file.binary,
-            truncated: file.truncated,
-        })
-        .collect();
+    let summaries: Vec<FileSummary> = files.into_iter().map(FileSummary::from).collect();
 
     if grouping == ListGrouping::None {
         ListSummaryOutput {
             files: Some(summaries),
             groups: None,
+            next_cursor: None,
         }
     } else {
         let groups = group_summaries(summaries, grouping);
         ListSummaryOutput {
             files: None,
             groups: Some(groups),
+            next_cursor: None,
+        }
+    }
+}
+
+fn build_stat_output(files: Vec<FileEntry>, grouping: ListGrouping) -> ListStatOutput {
+    let stats = |files: Vec<FileEntry>| -> Vec<FileStat> {
+        files.into_iter().map(FileStat::from).collect()
+    };
+
+    if grouping == ListGrouping::None {
+        let files = stats(files);
+        ListStatOutput {
+            total: LineTotals::of(&files),
+            files: Some(files),
+            groups: None,
+            next_cursor: None,
+        }
+    } else {
+        let groups: Vec<ListStatGroup> = group_files(files, grouping)
+            .into_iter()
+            .map(|group| {
+                let files = stats(group.files);
+                ListStatGroup {
+                    name: group.name,
+                    total: LineTotals::of(&files),
+                    files,
+                }
+            })
+            .collect();
+        ListStatOutput {
+            total: LineTotals::of(groups.iter().flat_map(|group| &group.files)),
+            files: None,
+            groups: Some(groups),
+            next_cursor: None,
         }
     }
 }
@@ -746,12 +2211,7 @@ fn group_summaries(files: Vec<FileSummary>, grouping: ListGrouping) -> Vec<ListS
     let mut index: HashMap<String, usize> = HashMap::new();
 
     for file in files {
-        let key = match grouping {
-            ListGrouping::Directory => directory_group(&file.path),
-            ListGrouping::Extension => extension_group(&file.path),
-            ListGrouping::Status => file.status.clone(),
-            ListGrouping::None => String::new(),
-        };
+        let key = group_key(&file.path, &file.status, grouping);
 
         if let Some(position) = index.get(&key).copied() {
             groups[position].files.push(file);
@@ -767,7 +2227,7 @@ fn group_summaries(files: Vec<FileSummary>, grouping: ListGrouping) -> Vec<ListS
     groups
 }
 
-fn build_spec_template(files: Vec<FileEntry>) -> SpecTemplateOutput {
+fn build_spec_template(files: Vec<FileEntry>, fuzzy: bool) -> SpecTemplateOutput {
     let mut output = HashMap::new();
 
     for file in files {
@@ -783,13 +2243,30 @@ fn build_spec_template(files: Vec<FileEntry>) -> SpecTemplateOutput {
             continue;
         }
 
-        let ids = file.hunks.into_iter().map(|hunk| hunk.id).collect();
-        output.insert(file.path, SpecTemplateEntry::Ids { ids });
+        let entry = if fuzzy {
+            let hunks = file.hunks.into_iter().map(SpecTemplateHunk::from).collect();
+            SpecTemplateEntry::Hunks { hunks }
+        } else {
+            let ids = file.hunks.into_iter().map(|hunk| hunk.id).collect();
+            SpecTemplateEntry::Ids { ids }
+        };
+        output.insert(file.path, entry);
     }
 
     SpecTemplateOutput {
         files: output,
         default: "reset".to_string(),
+        fuzzy,
+        expect: None,
+    }
+}
+
+fn group_key(path: &str, status: &str, grouping: ListGrouping) -> String {
+    match grouping {
+        ListGrouping::Directory => directory_group(path),
+        ListGrouping::Extension => extension_group(path),
+        ListGrouping::Status => status.to_string(),
+        ListGrouping::None => String::new(),
     }
 }
 
@@ -809,24 +2286,63 @@ fn extension_group(path: &str) -> String {
     }
 }
 
-fn render_text_output(output: &ListOutput) -> String {
+/// ANSI styling for text output; a no-op when color is off.
+#[derive(Debug, Clone, Copy)]
+struct Palette {
+    color: bool,
+}
+
+impl Palette {
+    const BOLD: &'static str = "1";
+    const DIM: &'static str = "2";
+    const RED: &'static str = "31";
+    const GREEN: &'static str = "32";
+    const YELLOW: &'static str = "33";
+    const CYAN: &'static str = "36";
+
+    fn new(choice: ColorChoice, is_terminal: bool) -> Self {
+        let color = match choice {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                is_terminal && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+            }
+        };
+        Self { color }
+    }
+
+    fn paint(self, style: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{style}m{text}\x1b[0m")
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn status(self, status: &str) -> String {
+        let style = match status {
+            "added" => Self::GREEN,
+            "removed" => Self::RED,
+            _ => Self::YELLOW,
+        };
+        self.paint(style, status_char(status))
+    }
+}
+
+fn render_text_output(output: &ListOutput, palette: Palette, columns: Option<usize>) -> String {
     let mut lines = Vec::new();
 
     if let Some(groups) = &output.groups {
         for (index, group) in groups.iter().enumerate() {
-            let name = if group.name == "." || group.name.is_empty() {
-                "<root>"
-            } else {
-                group.name.as_str()
-            };
-            lines.push(format!("{}:", name));
-            format_files_text(&mut lines, &group.files);
+            let title = format!("{}:", group_title(&group.name));
+            lines.push(palette.paint(Palette::BOLD, &title));
+            format_files_text(&mut lines, &group.files, palette, columns);
             if index + 1 < groups.len() {
                 lines.push(String::new());
             }
         }
     } else if let Some(files) = &output.files {
-        format_files_text(&mut lines, files);
+        format_files_text(&mut lines, files, palette, columns);
     }
 
     if lines.is_empty() {
@@ -838,24 +2354,20 @@ fn render_text_output(output: &ListOutput) -> String {
     output
 }
 
-fn render_text_summary_output(output: &ListSummaryOutput) -> String {
+fn render_text_summary_output(output: &ListSummaryOutput, palette: Palette) -> String {
     let mut lines = Vec::new();
 
     if let Some(groups) = &output.groups {
         for (index, group) in groups.iter().enumerate() {
-            let name = if group.name == "." || group.name.is_empty() {
-                "<root>"
-            } else {
-                group.name.as_str()
-            };
-            lines.push(format!("{}:", name));
-            format_summary_text(&mut lines, &group.files);
+            let title = format!("{}:", group_title(&group.name));
+            lines.push(palette.paint(Palette::BOLD, &title));
+            format_summary_text(&mut lines, &group.files, palette);
             if index + 1 < groups.len() {
                 lines.push(String::new());
             }
         }
     } else if let Some(files) = &output.files {
-        format_summary_text(&mut lines, files);
+        format_summary_text(&mut lines, files, palette);
     }
 
     if lines.is_empty() {
@@ -867,11 +2379,129 @@ fn render_text_summary_output(output: &ListSummaryOutput) -> String {
     output
 }
 
-fn format_files_text(lines: &mut Vec<String>, files: &[FileEntry]) {
+/// Render `--stat` as a diffstat: a histogram bar per file, its hunks'
+/// line counts below it, and totals per group and overall.
+fn render_text_stat_output(output: &ListStatOutput, palette: Palette) -> String {
+    let all: Vec<&FileStat> = match (&output.groups, &output.files) {
+        (Some(groups), _) => groups.iter().flat_map(|group| &group.files).collect(),
+        (None, Some(files)) => files.iter().collect(),
+        (None, None) => Vec::new(),
+    };
+    if all.is_empty() {
+        return String::new();
+    }
+    let layout = StatLayout {
+        path_width: all
+            .iter()
+            .map(|file| file.path.chars().count())
+            .max()
+            .unwrap_or(0),
+        count_width: all
+            .iter()
+            .map(|file| (file.added + file.removed).to_string().len())
+            .max()
+            .unwrap_or(1),
+        max_changed: all
+            .iter()
+            .map(|file| file.added + file.removed)
+            .max()
+            .unwrap_or(0),
+    };
+
+    let mut lines = Vec::new();
+    if let Some(groups) = &output.groups {
+        for group in groups {
+            let title = format!(
+                "{}: {}",
+                group_title(&group.name),
+                format_totals(group.total)
+            );
+            lines.push(palette.paint(Palette::BOLD, &title));
+            format_stat_text(&mut lines, &group.files, layout, palette);
+            lines.push(String::new());
+        }
+    } else if let Some(files) = &output.files {
+        format_stat_text(&mut lines, files, layout, palette);
+    }
+    lines.push(format_totals(output.total));
+
+    let mut output = lines.join("\n");
+    output.push('\n');
+    output
+}
+
+/// Column widths and bar scale shared by every `--stat` row.
+#[derive(Debug, Clone, Copy)]
+struct StatLayout {
+    path_width: usize,
+    count_width: usize,
+    max_changed: usize,
+}
+
+fn format_stat_text(
+    lines: &mut Vec<String>,
+    files: &[FileStat],
+    layout: StatLayout,
+    palette: Palette,
+) {
+    // Scale bars down only when the largest file would overflow them.
+    let scale = |n: usize| {
+        if n == 0 || layout.max_changed <= STAT_BAR_WIDTH {
+            n
+        } else {
+            (n * STAT_BAR_WIDTH / layout.max_changed).max(1)
+        }
+    };
+
     for file in files {
-        lines.push(format_file_header(file));
+        let path = format!("{:<width$}", file.path, width = layout.path_width);
+        let mut line = format!("{} {path} | ", palette.status(&file.status));
+        if file.binary == Some(true) {
+            line.push_str("binary");
+        } else {
+            line.push_str(&format!(
+                "{:>width$} {}{}",
+                file.added + file.removed,
+                palette.paint(Palette::GREEN, &"+".repeat(scale(file.added))),
+                palette.paint(Palette::RED, &"-".repeat(scale(file.removed))),
+                width = layout.count_width
+            ));
+        }
+        if let Some(rename) = &file.rename {
+            line.push_str(&format!(" ({} -> {})", rename.from, rename.to));
+        }
+        if file.truncated == Some(true) {
+            line.push_str(" [truncated]");
+        }
+        lines.push(line.trim_end().to_string());
+
         for hunk in &file.hunks {
-            lines.push(format!(
+            let caption = format!(
+                "    hunk {} {} {} +{} -{}",
+                hunk.index, hunk.hunk_type, hunk.id, hunk.added, hunk.removed
+            );
+            lines.push(palette.paint(Palette::DIM, &caption));
+        }
+    }
+}
+
+fn format_totals(total: LineTotals) -> String {
+    format!(
+        "{} files, {} hunks, +{} -{}",
+        total.files, total.hunks, total.added, total.removed
+    )
+}
+
+fn format_files_text(
+    lines: &mut Vec<String>,
+    files: &[FileEntry],
+    palette: Palette,
+    columns: Option<usize>,
+) {
+    for file in files {
+        lines.push(format_file_header(file, palette));
+        for (hunk, hunk_lines) in file.hunks.iter().zip(file_lines(&file.hunks)) {
+            let mut caption = format!(
                 "  hunk {} {} {} (before {}+{} after {}+{})",
                 hunk.index,
                 hunk.hunk_type,
@@ -880,26 +2510,198 @@ fn format_files_text(lines: &mut Vec<String>, files: &[FileEntry]) {
                 hunk.before_range.length,
                 hunk.after_range.start,
                 hunk.after_range.length,
-            ));
-            if !hunk.removed.is_empty() {
-                for line in hunk.removed.lines() {
-                    lines.push(format!("    - {}", line));
-                }
+            );
+            if hunk.truncated == Some(true) {
+                caption.push_str(" [truncated]");
             }
-            if !hunk.added.is_empty() {
-                for line in hunk.added.lines() {
-                    lines.push(format!("    + {}", line));
-                }
+            lines.push(palette.paint(Palette::CYAN, &caption));
+
+            let width = line_number_width(&hunk_lines);
+            if let Some(columns) = columns {
+                format_side_by_side(lines, &hunk_lines, width, columns, palette);
+                continue;
+            }
+            for line in hunk_lines {
+                let number = |n: Option<usize>| match n {
+                    Some(n) => format!("{n:>width$}"),
+                    None => " ".repeat(width),
+                };
+                let numbers = format!("{} {}", number(line.before), number(line.after));
+                let (sign, style) = match line.kind {
+                    LineKind::Context => (' ', None),
+                    LineKind::Removed => ('-', Some(Palette::RED)),
+                    LineKind::Added => ('+', Some(Palette::GREEN)),
+                };
+                let text = format!("{sign} {}", line.text);
+                lines.push(format!(
+                    "    {} {}",
+                    palette.paint(Palette::DIM, &numbers),
+                    match style {
+                        Some(style) => palette.paint(style, &text),
+                        None => text,
+                    }
+                ));
+            }
+        }
+    }
+}
+
+/// Digits needed for the largest line number of a hunk.
+fn line_number_width(lines: &[HunkLine]) -> usize {
+    lines
+        .iter()
+        .flat_map(|line| [line.before, line.after])
+        .flatten()
+        .max()
+        .unwrap_or(0)
+        .to_string()
+        .len()
+}
+
+/// Render hunk lines as two columns fitting `columns` characters: removed
+/// lines face the added lines that replaced them, with changed words
+/// highlighted, and long lines wrap within their column.
+fn format_side_by_side(
+    lines: &mut Vec<String>,
+    hunk_lines: &[HunkLine],
+    number_width: usize,
+    columns: usize,
+    palette: Palette,
+) {
+    const INDENT: &str = "    ";
+    const SEPARATOR: &str = " │ ";
+    let cell_width = (columns.saturating_sub(INDENT.len() + 3) / 2).max(number_width + 3 + 8);
+    let text_width = cell_width - number_width - 3;
+
+    for (before, after) in side_by_side(hunk_lines) {
+        let (left, right) = match (before, after) {
+            (Some(before), Some(after)) if before.kind != after.kind => {
+                inline_spans(before.text, after.text)
+            }
+            _ => (
+                before.map(|line| vec![(line.text.to_string(), false)]),
+                after.map(|line| vec![(line.text.to_string(), false)]),
+            ),
+        };
+        let left = format_cell(
+            before,
+            left,
+            LineKind::Removed,
+            number_width,
+            text_width,
+            palette,
+        );
+        let right = format_cell(
+            after,
+            right,
+            LineKind::Added,
+            number_width,
+            text_width,
+            palette,
+        );
+        for row in 0..left.len().max(right.len()) {
+            let blank = " ".repeat(cell_width);
+            let line = format!(
+                "{INDENT}{}{}{}",
+                left.get(row).unwrap_or(&blank),
+                palette.paint(Palette::DIM, SEPARATOR),
+                right.get(row).unwrap_or(&blank)
+            );
+            lines.push(line.trim_end().to_string());
+        }
+    }
+}
+
+/// Pieces of a line's text, flagged when they differ from the other side.
+type Spans = Vec<(String, bool)>;
+
+/// Split a replaced line pair into word spans, marking the changed ones.
+fn inline_spans(before: &str, after: &str) -> (Option<Spans>, Option<Spans>) {
+    let diff = TextDiff::from_words(before, after);
+    let (mut left, mut right) = (Vec::new(), Vec::new());
+    for change in diff.iter_all_changes() {
+        let span = change.value().to_string();
+        match change.tag() {
+            ChangeTag::Equal => {
+                left.push((span.clone(), false));
+                right.push((span, false));
+            }
+            ChangeTag::Delete => left.push((span, true)),
+            ChangeTag::Insert => right.push((span, true)),
+        }
+    }
+    (Some(left), Some(right))
+}
+
+/// One side of a side-by-side row, wrapped and padded to its cell width.
+fn format_cell(
+    line: Option<&HunkLine>,
+    spans: Option<Spans>,
+    side: LineKind,
+    number_width: usize,
+    text_width: usize,
+    palette: Palette,
+) -> Vec<String> {
+    let (Some(line), Some(spans)) = (line, spans) else {
+        return Vec::new();
+    };
+    let number = if side == LineKind::Removed {
+        line.before
+    } else {
+        line.after
+    };
+    let (sign, style) = match line.kind {
+        LineKind::Context => (' ', None),
+        LineKind::Removed => ('-', Some(Palette::RED)),
+        LineKind::Added => ('+', Some(Palette::GREEN)),
+    };
+
+    // Break the spans into rows of at most `text_width` characters.
+    let mut rows: Vec<Spans> = vec![Vec::new()];
+    let mut row_len = 0;
+    for (text, changed) in spans {
+        for c in text.replace('\t', "    ").chars() {
+            if row_len == text_width {
+                rows.push(Vec::new());
+                row_len = 0;
             }
+            let row = rows.last_mut().expect("rows is never empty");
+            match row.last_mut() {
+                Some((span, span_changed)) if *span_changed == changed => span.push(c),
+                _ => row.push((c.to_string(), changed)),
+            }
+            row_len += 1;
         }
     }
+
+    rows.into_iter()
+        .enumerate()
+        .map(|(index, row)| {
+            let prefix = match (index, number) {
+                (0, Some(number)) => format!("{number:>number_width$} {sign} "),
+                (0, None) => format!("{} {sign} ", " ".repeat(number_width)),
+                _ => " ".repeat(number_width + 3),
+            };
+            let len: usize = row.iter().map(|(text, _)| text.chars().count()).sum();
+            let mut cell = palette.paint(Palette::DIM, &prefix);
+            for (text, changed) in row {
+                cell.push_str(&match style {
+                    Some(style) if changed => palette.paint(&format!("1;{style}"), &text),
+                    Some(style) => palette.paint(style, &text),
+                    None => text,
+                });
+            }
+            cell.push_str(&" ".repeat(text_width - len));
+            cell
+        })
+        .collect()
 }
 
-fn format_summary_text(lines: &mut Vec<String>, files: &[FileSummary]) {
+fn format_summary_text(lines: &mut Vec<String>, files: &[FileSummary], palette: Palette) {
     for file in files {
         let mut line = format!(
             "{} {} ({} hunks)",
-            status_char(&file.status),
+            palette.status(&file.status),
             file.path,
             file.hunk_count
         );
@@ -916,8 +2718,12 @@ fn format_summary_text(lines: &mut Vec<String>, files: &[FileSummary]) {
     }
 }
 
-fn format_file_header(file: &FileEntry) -> String {
-    let mut header = format!("{} {}", status_char(&file.status), file.path);
+fn format_file_header(file: &FileEntry, palette: Palette) -> String {
+    let mut header = format!(
+        "{} {}",
+        palette.status(&file.status),
+        palette.paint(Palette::BOLD, &file.path)
+    );
     if let Some(rename) = &file.rename {
         header.push_str(&format!(" ({} -> {})", rename.from, rename.to));
     }
@@ -930,6 +2736,224 @@ fn format_file_header(file: &FileEntry) -> String {
     header
 }
 
+fn render_markdown_result(result: &ListResult) -> String {
+    let mut lines = Vec::new();
+    match result {
+        ListResult::Full(output) => {
+            if let Some(groups) = &output.groups {
+                for group in groups {
+                    lines.push(format!("## {}", group_title(&group.name)));
+                    lines.push(String::new());
+                    for file in &group.files {
+                        format_file_markdown(&mut lines, file, "###");
+                    }
+                }
+            } else if let Some(files) = &output.files {
+                for file in files {
+                    format_file_markdown(&mut lines, file, "##");
+                }
+            }
+        }
+        ListResult::Files(summary) => {
+            if let Some(groups) = &summary.groups {
+                for group in groups {
+                    lines.push(format!("## {}", group_title(&group.name)));
+                    lines.push(String::new());
+                    format_summary_markdown(&mut lines, &group.files);
+                    lines.push(String::new());
+                }
+            } else if let Some(files) = &summary.files {
+                format_summary_markdown(&mut lines, files);
+            }
+        }
+        ListResult::Stat(stat) => {
+            if let Some(groups) = &stat.groups {
+                for group in groups {
+                    lines.push(format!("## {}", group_title(&group.name)));
+                    lines.push(String::new());
+                    format_stat_markdown(&mut lines, &group.files);
+                    lines.push(String::new());
+                    lines.push(format!("_{}_", format_totals(group.total)));
+                    lines.push(String::new());
+                }
+            } else if let Some(files) = &stat.files {
+                format_stat_markdown(&mut lines, files);
+                lines.push(String::new());
+            }
+            lines.push(format!("**Total:** {}", format_totals(stat.total)));
+        }
+        ListResult::SpecTemplate(_) => {}
+    }
+
+    while lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
+    if let Some(cursor) = result.next_cursor() {
+        lines.push(String::new());
+        lines.push(format!(
+            "_More to list: continue with `--cursor {cursor}`._"
+        ));
+    }
+    if lines.is_empty() {
+        return String::new();
+    }
+    let mut output = lines.join("\n");
+    output.push('\n');
+    output
+}
+
+/// A heading per file, then a caption and a fenced diff block per hunk.
+fn format_file_markdown(lines: &mut Vec<String>, file: &FileEntry, heading: &str) {
+    let mut title = format!("{heading} `{}` ({}", file.path, file.status);
+    if let Some(rename) = &file.rename {
+        title.push_str(&format!(" from `{}`", rename.from));
+    }
+    title.push(')');
+    lines.push(title);
+    lines.push(String::new());
+    if file.binary == Some(true) {
+        lines.push("_Binary file._".to_string());
+        lines.push(String::new());
+    }
+
+    let info = match markdown_language(&file.path) {
+        Some(language) => format!("diff {language}"),
+        None => "diff".to_string(),
+    };
+    for (hunk, hunk_lines) in file.hunks.iter().zip(file_lines(&file.hunks)) {
+        let mut caption = format!(
+            "Hunk {} · `{}` · {} · `-{},{} +{},{}`",
+            hunk.index,
+            hunk.id,
+            hunk.hunk_type,
+            hunk.before_range.start,
+            hunk.before_range.length,
+            hunk.after_range.start,
+            hunk.after_range.length,
+        );
+        if hunk.truncated == Some(true) {
+            caption.push_str(" · truncated");
+        }
+        lines.push(caption);
+        lines.push(String::new());
+
+        let body: Vec<String> = hunk_lines
+            .into_iter()
+            .map(|line| {
+                let prefix = match line.kind {
+                    LineKind::Context => ' ',
+                    LineKind::Removed => '-',
+                    LineKind::Added => '+',
+                };
+                format!("{prefix}{}", line.text)
+            })
+            .collect();
+        let fence = markdown_fence(&body);
+        lines.push(format!("{fence}{info}"));
+        lines.extend(body);
+        lines.push(fence);
+        lines.push(String::new());
+    }
+}
+
+fn format_summary_markdown(lines: &mut Vec<String>, files: &[FileSummary]) {
+    for file in files {
+        let mut line = format!(
+            "- `{}` ({}, {} hunks)",
+            file.path, file.status, file.hunk_count
+        );
+        if let Some(rename) = &file.rename {
+            line.push_str(&format!(" from `{}`", rename.from));
+        }
+        if file.binary == Some(true) {
+            line.push_str(" · binary");
+        }
+        if file.truncated == Some(true) {
+            line.push_str(" · truncated");
+        }
+        lines.push(line);
+    }
+}
+
+/// A table row per file with its added and removed line counts.
+fn format_stat_markdown(lines: &mut Vec<String>, files: &[FileStat]) {
+    lines.push("| File | Status | Hunks | Added | Removed |".to_string());
+    lines.push("|------|--------|------:|------:|--------:|".to_string());
+    for file in files {
+        let mut path = format!("`{}`", file.path);
+        if let Some(rename) = &file.rename {
+            path.push_str(&format!(" from `{}`", rename.from));
+        }
+        if file.binary == Some(true) {
+            path.push_str(" · binary");
+        }
+        if file.truncated == Some(true) {
+            path.push_str(" · truncated");
+        }
+        lines.push(format!(
+            "| {path} | {} | {} | +{} | -{} |",
+            file.status,
+            file.hunks.len(),
+            file.added,
+            file.removed
+        ));
+    }
+}
+
+/// A code fence longer than any backtick run in `body`.
+fn markdown_fence(body: &[String]) -> String {
+    let longest = body
+        .iter()
+        .flat_map(|line| line.split(|c| c != '`'))
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    "`".repeat(longest.max(2) + 1)
+}
+
+/// Highlighting hint for a fenced block, from the file extension.
+fn markdown_language(path: &str) -> Option<&'static str> {
+    let extension = Path::new(path).extension()?.to_str()?;
+    Some(match extension.to_ascii_lowercase().as_str() {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "jsx" => "jsx",
+        "ts" | "mts" | "cts" => "typescript",
+        "tsx" => "tsx",
+        "go" => "go",
+        "rb" => "ruby",
+        "java" => "java",
+        "kt" | "kts" => "kotlin",
+        "swift" => "swift",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" => "cpp",
+        "cs" => "csharp",
+        "php" => "php",
+        "lua" => "lua",
+        "nix" => "nix",
+        "sh" | "bash" | "zsh" => "bash",
+        "sql" => "sql",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "scss" => "scss",
+        "md" | "markdown" => "markdown",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "xml" => "xml",
+        _ => return None,
+    })
+}
+
+fn group_title(name: &str) -> &str {
+    if name == "." || name.is_empty() {
+        "<root>"
+    } else {
+        name
+    }
+}
+
 fn status_char(status: &str) -> &'static str {
     match status {
         "modified" => "M",
@@ -941,6 +2965,10 @@ fn status_char(status: &str) -> &'static str {
     }
 }
 
+/// File listing the paths changed by a selection command, one per line,
+/// relative to `$left` and `$right`.
+pub const CHANGED_PATHS_ENV: &str = "JJ_HUNK_CHANGED_PATHS";
+
 /// Select hunks (called by jj --tool)
 pub fn select(left: &str, right: &str) -> Result<()> {
     let spec_path = std::env::var("JJ_HUNK_SELECTION").ok();
@@ -954,44 +2982,130 @@ pub fn select(left: &str, right: &str) -> Result<()> {
         return Ok(());
     };
 
-    let left_path = Path::new(left);
-    let right_path = Path::new(right);
+    let changed = match std::env::var_os(CHANGED_PATHS_ENV) {
+        Some(path) => {
+            let content = fs::read_to_string(&path).with_context(|| {
+                format!(
+                    "Failed to read changed paths from {}",
+                    Path::new(&path).display()
+                )
+            })?;
+            Some(content.lines().map(str::to_string).collect())
+        }
+        None => None,
+    };
 
-    // Get all files in both directories
-    let left_files = list_files(left_path);
-    let right_files = list_files(right_path);
-    let all_files: HashSet<_> = left_files.union(&right_files).cloned().collect();
+    select_dirs(
+        &spec,
+        &HunkCache::from_env(),
+        Path::new(left),
+        Path::new(right),
+        changed,
+    )
+}
 
-    for filepath in all_files {
-        let file_spec = spec.files.get(&filepath);
+/// Apply `spec` to `right`, looking only at the `changed` paths when known
+/// and otherwise at every file that differs between `left` and `right`.
+fn select_dirs(
+    spec: &Spec,
+    cache: &HunkCache,
+    left_path: &Path,
+    right_path: &Path,
+    changed: Option<Vec<String>>,
+) -> Result<()> {
+    let known = changed.is_some();
+    let mut all_files: Vec<_> = match changed {
+        Some(paths) => paths.into_iter().filter(|path| !path.is_empty()).collect(),
+        None => {
+            let left_files = list_files(left_path);
+            let right_files = list_files(right_path);
+            left_files.union(&right_files).cloned().collect()
+        }
+    };
+    all_files.sort();
+    all_files.dedup();
 
-        match file_spec {
-            Some(FileSpec::Action {
-                action: Action::Keep,
-            }) => {
-                // Keep as-is
-            }
-            Some(FileSpec::Action {
-                action: Action::Reset,
-            }) => {
-                reset_file(left_path, right_path, &filepath)?;
-            }
-            Some(FileSpec::Selection(selection)) => {
-                let selection = selection.to_selection();
-                apply_hunk_selection(left_path, right_path, &filepath, &selection)?;
-            }
-            None => {
-                // Use default
-                if spec.default == DefaultAction::Reset {
-                    reset_file(left_path, right_path, &filepath)?;
-                }
-            }
+    let results = parallel_map(&all_files, |filepath| {
+        if !known && !file_differs(left_path, right_path, filepath) {
+            return Ok(Vec::new());
+        }
+        select_file(spec, cache, left_path, right_path, filepath)
+    });
+    for (filepath, result) in all_files.iter().zip(results) {
+        for relocation in result? {
+            report_relocation(filepath, &relocation);
         }
     }
 
     Ok(())
 }
 
+/// Apply the spec to one changed file of `$right`, returning fuzzy
+/// relocations.
+fn select_file(
+    spec: &Spec,
+    cache: &HunkCache,
+    left_path: &Path,
+    right_path: &Path,
+    filepath: &str,
+) -> Result<Vec<Relocation>> {
+    match file_choice(spec, filepath) {
+        FileChoice::Keep => {}
+        FileChoice::Reset => reset_file(left_path, right_path, filepath)?,
+        FileChoice::Hunks(selection) => {
+            return apply_hunk_selection(
+                left_path, right_path, filepath, selection, spec.fuzzy, cache,
+            );
+        }
+    }
+
+    Ok(Vec::new())
+}
+
+/// What a spec does with one changed file.
+pub(crate) enum FileChoice {
+    /// Keep the file as it is after the change.
+    Keep,
+    /// Restore the file as it was before the change.
+    Reset,
+    /// Keep only the selected hunks.
+    Hunks(HunkSelection),
+}
+
+/// Decide what `spec` does with the changed file `filepath`.
+pub(crate) fn file_choice(spec: &Spec, filepath: &str) -> FileChoice {
+    match spec.files.get(filepath) {
+        Some(FileSpec::Action {
+            action: Action::Keep,
+        }) => FileChoice::Keep,
+        Some(FileSpec::Action {
+            action: Action::Reset,
+        }) => FileChoice::Reset,
+        Some(FileSpec::Selection(selection)) => FileChoice::Hunks(selection.to_selection()),
+        None if spec.default == DefaultAction::Reset => FileChoice::Reset,
+        None => FileChoice::Keep,
+    }
+}
+
+/// Keep the hunks of `selection` in the change from `before` to `after`,
+/// returning the resulting contents and any fuzzy relocations.
+pub(crate) fn select_hunks(
+    before: &str,
+    after: &str,
+    mut selection: HunkSelection,
+    fuzzy: bool,
+    cache: &HunkCache,
+) -> (String, Vec<Relocation>) {
+    let diff = cache.file_diff(before, after);
+    // Fuzzy specs relocate stale ids against the current hunks.
+    let relocations = if fuzzy {
+        selection.relocate(diff.hunks())
+    } else {
+        Vec::new()
+    };
+    (diff.apply(&selection), relocations)
+}
+
 fn list_files(dir: &Path) -> HashSet<String> {
     let mut files = HashSet::new();
     if !dir.exists() {
@@ -1011,6 +3125,25 @@ fn list_files(dir: &Path) -> HashSet<String> {
     files
 }
 
+/// Whether `filepath` differs between `left` and `right`.
+///
+/// Sizes and permissions are compared before reading any content.
+fn file_differs(left: &Path, right: &Path, filepath: &str) -> bool {
+    let left_file = left.join(filepath);
+    let right_file = right.join(filepath);
+    let (Ok(left_meta), Ok(right_meta)) = (fs::metadata(&left_file), fs::metadata(&right_file))
+    else {
+        return true;
+    };
+    if left_meta.len() != right_meta.len() || left_meta.permissions() != right_meta.permissions() {
+        return true;
+    }
+    match (fs::read(&left_file), fs::read(&right_file)) {
+        (Ok(left_bytes), Ok(right_bytes)) => left_bytes != right_bytes,
+        _ => true,
+    }
+}
+
 fn reset_file(left: &Path, right: &Path, filepath: &str) -> Result<()> {
     let left_file = left.join(filepath);
     let right_file = right.join(filepath);
@@ -1030,8 +3163,10 @@ fn apply_hunk_selection(
     left: &Path,
     right: &Path,
     filepath: &str,
-    selection: &HunkSelection,
-) -> Result<()> {
+    selection: HunkSelection,
+    fuzzy: bool,
+    cache: &HunkCache,
+) -> Result<Vec<Relocation>> {
     let left_file = left.join(filepath);
     let right_file = right.join(filepath);
 
@@ -1044,13 +3179,26 @@ fn apply_hunk_selection(
     let after = if right_file.exists() {
         fs::read_to_string(&right_file)?
     } else {
-        return Ok(());
+        return Ok(Vec::new());
     };
 
-    let result = apply_selected_hunks(&before, &after, selection);
+    let (result, relocations) = select_hunks(&before, &after, selection, fuzzy, cache);
 
-    fs::write(&right_file, result)?;
-    Ok(())
+    if result != after {
+        fs::write(&right_file, result)?;
+    }
+    Ok(relocations)
+}
+
+pub(crate) fn report_relocation(filepath: &str, relocation: &Relocation) {
+    match relocation {
+        Relocation::Moved { from, to, index } => {
+            eprintln!("jj-hunk: relocated {from} in {filepath} to hunk {index} ({to})");
+        }
+        Relocation::Missing { id } => {
+            eprintln!("jj-hunk: could not relocate {id} in {filepath}; leaving it unselected");
+        }
+    }
 }
 
 fn resolve_spec_input(spec: Option<&str>, spec_file: Option<&str>) -> Result<String> {
@@ -1077,90 +3225,1174 @@ fn resolve_spec_input(spec: Option<&str>, spec_file: Option<&str>) -> Result<Str
     Ok(spec.to_string())
 }
 
-fn run_jj_with_selection(args: &[&str], spec: Option<&str>, spec_file: Option<&str>) -> Result<()> {
+fn run_jj_with_selection(
+    session: &mut Session,
+    command: SelectionCommand,
+    spec: Option<&str>,
+    spec_file: Option<&str>,
+) -> Result<()> {
     let spec_content = resolve_spec_input(spec, spec_file)?;
-    let temp_file = std::env::temp_dir().join(format!("jj-hunk-{}.spec", std::process::id()));
-    fs::write(&temp_file, spec_content)?;
-
-    let config_args = jj_hunk_tool_config_args()?;
-
-    let status = Command::new("jj")
-        .args(&config_args)
-        .args(args)
-        .env("JJ_HUNK_SELECTION", &temp_file)
-        .status()
-        .context("Failed to run jj")?;
+    verify_expectation(session, &Spec::from_str(&spec_content)?, command.rev())?;
+    let mut temp_file = tempfile::Builder::new()
+        .prefix("jj-hunk-")
+        .suffix(".spec")
+        .tempfile()
+        .context("Failed to create spec file")?;
+    temp_file.write_all(spec_content.as_bytes())?;
+
+    let operation_before = session.backend.operation_id()?;
+    let cache = session.hunk_cache();
+    session
+        .backend
+        .run_selection(command, temp_file.path(), cache.dir())?;
+
+    write_undo_record(
+        session,
+        &UndoRecord {
+            command: command.name().to_string(),
+            operation_before,
+            operation_after: session.backend.operation_id()?,
+        },
+    )
+}
 
-    fs::remove_file(&temp_file).ok();
+/// Operations recorded around the last mutating jj-hunk command.
+#[derive(Debug, Serialize, Deserialize)]
+struct UndoRecord {
+    command: String,
+    operation_before: String,
+    operation_after: String,
+}
 
-    if !status.success() {
-        anyhow::bail!("jj command failed");
-    }
+/// Remove every entry from the on-disk hunk cache
+pub fn cache_clear(session: &mut Session) -> Result<()> {
+    let removed = session.hunk_cache().clear()?;
+    println!("Removed {removed} cached hunk entries");
     Ok(())
 }
 
-fn jj_hunk_tool_config_args() -> Result<Vec<String>> {
-    let mut args = Vec::new();
-
-    if !jj_config_key_exists(JJ_HUNK_PROGRAM_KEY) {
-        let program = std::env::current_exe()
-            .context("Failed to determine current jj-hunk executable path")?;
-        let program = program
-            .to_str()
-            .ok_or_else(|| anyhow::anyhow!("jj-hunk executable path is not valid UTF-8"))?;
-        args.push("--config".to_string());
-        args.push(format!("{JJ_HUNK_PROGRAM_KEY}={}", toml_string(program)));
+/// Restore the repo to the operation before the last split/commit/squash
+pub fn undo(session: &mut Session, force: bool) -> Result<()> {
+    let path = undo_record_path(session)?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            anyhow::bail!("Nothing to undo: no jj-hunk split/commit/squash recorded")
+        }
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let record: UndoRecord = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    let current = session.backend.operation_id()?;
+    if current != record.operation_after && !force {
+        anyhow::bail!(
+            "Other operations ran since `jj-hunk {}` (repo is at {current}, expected {}); \
+             use --force to restore operation {} anyway",
+            record.command,
+            record.operation_after,
+            record.operation_before
+        );
     }
 
-    if !jj_config_key_exists(JJ_HUNK_EDIT_ARGS_KEY) {
-        args.push("--config".to_string());
-        args.push(format!(
-            r#"{JJ_HUNK_EDIT_ARGS_KEY}=["select", "$left", "$right"]"#
-        ));
-    }
+    session
+        .backend
+        .restore_operation(&record.operation_before)?;
 
-    Ok(args)
+    fs::remove_file(&path).ok();
+    Ok(())
 }
 
-fn jj_config_key_exists(key: &str) -> bool {
-    Command::new("jj")
-        .args(["config", "get", key])
-        .output()
-        .map(|output| output.status.success())
-        .unwrap_or(false)
+fn write_undo_record(session: &Session, record: &UndoRecord) -> Result<()> {
+    let path = undo_record_path(session)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, serde_json::to_string_pretty(record)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
 }
 
-fn toml_string(value: &str) -> String {
-    serde_json::to_string(value).expect("string serialization should not fail")
+fn undo_record_path(session: &Session) -> Result<PathBuf> {
+    Ok(session.backend.state_dir()?.join("undo.json"))
 }
 
 pub fn split(
+    session: &mut Session,
     spec: Option<&str>,
     spec_file: Option<&str>,
     message: &str,
     rev: Option<&str>,
 ) -> Result<()> {
-    let mut args = vec!["split", JJ_HUNK_TOOL_ARG, "-m", message];
-    if let Some(rev) = rev {
-        args.push("-r");
-        args.push(rev);
-    }
-    run_jj_with_selection(&args, spec, spec_file)
+    run_jj_with_selection(
+        session,
+        SelectionCommand::Split { message, rev },
+        spec,
+        spec_file,
+    )
 }
 
-pub fn commit(spec: Option<&str>, spec_file: Option<&str>, message: &str) -> Result<()> {
+pub fn commit(
+    session: &mut Session,
+    spec: Option<&str>,
+    spec_file: Option<&str>,
+    message: &str,
+) -> Result<()> {
     run_jj_with_selection(
-        &["commit", "-i", JJ_HUNK_TOOL_ARG, "-m", message],
+        session,
+        SelectionCommand::Commit { message },
         spec,
         spec_file,
     )
 }
 
-pub fn squash(spec: Option<&str>, spec_file: Option<&str>, rev: Option<&str>) -> Result<()> {
-    let mut args = vec!["squash", "-i", JJ_HUNK_TOOL_ARG];
-    if let Some(rev) = rev {
-        args.push("-r");
-        args.push(rev);
+pub fn squash(
+    session: &mut Session,
+    spec: Option<&str>,
+    spec_file: Option<&str>,
+    rev: Option<&str>,
+) -> Result<()> {
+    run_jj_with_selection(session, SelectionCommand::Squash { rev }, spec, spec_file)
+}
+
+/// Build a spec selecting the hunks in a unified diff, such as edited
+/// `list --format patch` output; `patch_path` may be `-` for stdin.
+///
+/// Patch hunks are matched to the current hunks of `rev` by the id in their
+/// `@@` trailer, or else by content. Hunks that match nothing are an error,
+/// since jj-hunk can only select whole hunks.
+pub fn spec_from_patch(
+    session: &mut Session,
+    patch_path: &str,
+    rev: Option<&str>,
+) -> Result<String> {
+    let text = if patch_path == "-" {
+        let mut buffer = String::new();
+        std::io::stdin()
+            .read_to_string(&mut buffer)
+            .context("Failed to read patch from stdin")?;
+        buffer
+    } else {
+        fs::read_to_string(patch_path)
+            .with_context(|| format!("Failed to read patch file {}", patch_path))?
+    };
+    let parsed = patch::parse(&text)?;
+    if parsed.is_empty() {
+        anyhow::bail!("Patch {patch_path} contains no file diffs");
+    }
+
+    let paths: HashSet<&str> = parsed
+        .iter()
+        .flat_map(|file| [file.old_path.as_deref(), file.new_path.as_deref()])
+        .flatten()
+        .collect();
+    let files = collect_files(session, &ListOptions::from(rev), |path| {
+        paths.contains(path)
+    })?;
+
+    let mut selected: HashMap<String, Option<Vec<String>>> = HashMap::new();
+    for patch_file in &parsed {
+        let file = files
+            .iter()
+            .find(|file| patch_targets(patch_file, file))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{} in the patch has no changes in {}",
+                    patch_file.path().unwrap_or("<unknown>"),
+                    rev.unwrap_or("@")
+                )
+            })?;
+        let entry = selected
+            .entry(file.path.clone())
+            .or_insert(Some(Vec::new()));
+        if patch_file.hunks.is_empty() {
+            // Binary files and pure renames carry no hunks; take them whole.
+            *entry = None;
+        } else if let Some(ids) = entry {
+            for id in match_patch_hunks(file, &patch_file.hunks)? {
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+    }
+
+    let files = selected
+        .into_iter()
+        .map(|(path, ids)| {
+            let entry = match ids {
+                Some(ids) => SpecTemplateEntry::Ids { ids },
+                None => SpecTemplateEntry::Action {
+                    action: "keep".to_string(),
+                },
+            };
+            (path, entry)
+        })
+        .collect();
+    Ok(serde_json::to_string(&SpecTemplateOutput {
+        files,
+        default: "reset".to_string(),
+        fuzzy: false,
+        expect: None,
+    })?)
+}
+
+fn patch_targets(patch_file: &ParsedFile, file: &FileEntry) -> bool {
+    let mut paths = vec![file.path.as_str()];
+    if let Some(rename) = &file.rename {
+        paths.extend([rename.from.as_str(), rename.to.as_str()]);
+    }
+    match patch_file.path() {
+        Some(path) => paths.contains(&path),
+        None => false,
+    }
+}
+
+/// Ids of the current hunks of `file` that `wanted` correspond to.
+fn match_patch_hunks(file: &FileEntry, wanted: &[ParsedHunk]) -> Result<Vec<String>> {
+    let same_change = |hunk: &Hunk, patch: &ParsedHunk| {
+        hunk.removed == patch.removed && hunk.added == patch.added
+    };
+    let mut used = HashSet::new();
+    let mut ids = Vec::new();
+
+    for patch_hunk in wanted {
+        let by_id = patch_hunk
+            .id
+            .as_ref()
+            .and_then(|id| file.hunks.iter().find(|hunk| &hunk.id == id));
+        let hunk = match by_id {
+            Some(hunk) if same_change(hunk, patch_hunk) => hunk,
+            Some(hunk) => anyhow::bail!(
+                "{}: {} was edited in the patch; only whole hunks can be selected",
+                file.path,
+                hunk.id
+            ),
+            None => file
+                .hunks
+                .iter()
+                .filter(|hunk| !used.contains(&hunk.index) && same_change(hunk, patch_hunk))
+                .min_by_key(|hunk| hunk.before_range.start.abs_diff(patch_hunk.before_start))
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "{}: patch hunk at line {} does not match any current hunk",
+                        file.path,
+                        patch_hunk.before_start
+                    )
+                })?,
+        };
+        if used.insert(hunk.index) {
+            ids.push(hunk.id.clone());
+        }
+    }
+
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::memory::MemoryBackend;
+    use crate::diff::get_hunks;
+
+    fn session(backend: MemoryBackend) -> Session {
+        Session::with_backend(Box::new(backend))
+    }
+
+    fn repo() -> MemoryBackend {
+        MemoryBackend::new()
+            .with_revision(
+                "base",
+                None,
+                &[("src/lib.rs", "one\ntwo\nthree\n"), ("old.txt", "bye\n")],
+            )
+            .with_revision(
+                "@",
+                Some("base"),
+                &[("src/lib.rs", "one\nTWO\nthree\n"), ("docs/new.md", "hi\n")],
+            )
+    }
+
+    fn listed_paths(value: &serde_json::Value) -> Vec<&str> {
+        value["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file["path"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn list_reports_added_removed_and_modified_files() {
+        let mut session = session(repo());
+        let value = list_value(&mut session, &ListOptions::default()).unwrap();
+
+        assert_eq!(
+            listed_paths(&value),
+            ["docs/new.md", "old.txt", "src/lib.rs"]
+        );
+        let files = value["files"].as_array().unwrap();
+        assert_eq!(files[0]["status"], "added");
+        assert_eq!(files[1]["status"], "removed");
+        assert_eq!(files[2]["status"], "modified");
+        assert_eq!(files[2]["hunks"][0]["removed"], "two\n");
+        assert_eq!(files[2]["hunks"][0]["added"], "TWO\n");
+    }
+
+    #[test]
+    fn list_applies_path_filters_and_spec_preview() {
+        let mut session = session(repo());
+
+        let options = ListOptions {
+            exclude: vec!["docs/**".to_string()],
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert_eq!(listed_paths(&value), ["old.txt", "src/lib.rs"]);
+
+        let options = ListOptions {
+            spec: Some(r#"{"files": {"src/lib.rs": {"hunks": [0]}}, "default": "reset"}"#.into()),
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert_eq!(listed_paths(&value), ["src/lib.rs"]);
+    }
+
+    #[test]
+    fn list_passes_filesets_to_the_backend() {
+        let mut session = session(repo());
+        let options = ListOptions {
+            paths: vec!["docs".to_string(), "glob:**/*.rs".to_string()],
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert_eq!(listed_paths(&value), ["docs/new.md", "src/lib.rs"]);
+
+        let options = ListOptions {
+            paths: vec!["glob:**/*.rs".to_string()],
+            exclude: vec!["src/**".to_string()],
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert!(listed_paths(&value).is_empty());
+    }
+
+    #[test]
+    fn parallel_map_keeps_input_order() {
+        let items: Vec<usize> = (0..100).collect();
+        let doubled = parallel_map(&items, |n| n * 2);
+        assert_eq!(doubled, items.iter().map(|n| n * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn list_keeps_summary_order_across_many_files() {
+        let paths: Vec<String> = (0..40).map(|n| format!("f{n:02}.txt")).collect();
+        let before: Vec<(&str, &str)> = paths.iter().map(|p| (p.as_str(), "a\n")).collect();
+        let after: Vec<(&str, &str)> = paths.iter().map(|p| (p.as_str(), "b\n")).collect();
+        let backend = MemoryBackend::new()
+            .with_revision("base", None, &before)
+            .with_revision("@", Some("base"), &after);
+        let mut session = session(backend);
+
+        let value = list_value(&mut session, &ListOptions::default()).unwrap();
+        assert_eq!(listed_paths(&value), paths);
+    }
+
+    #[test]
+    fn ndjson_emits_one_record_per_file_then_a_summary() {
+        let mut session = session(repo());
+        let options = ListOptions {
+            format: ListFormat::Ndjson,
+            group: ListGrouping::Status,
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        let records: Vec<serde_json::Value> = value
+            .as_str()
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0]["type"], "file");
+        assert_eq!(records[0]["path"], "docs/new.md");
+        assert_eq!(records[0]["group"], "added");
+        assert_eq!(records[2]["hunks"][0]["added"], "TWO\n");
+        assert_eq!(records[3]["type"], "summary");
+        assert_eq!(records[3]["files"], 3);
+        assert_eq!(records[3]["hunks"], 3);
+    }
+
+    #[test]
+    fn ndjson_file_mode_streams_summaries() {
+        let mut session = session(repo());
+        let options = ListOptions {
+            format: ListFormat::Ndjson,
+            mode: ListMode::Files,
+            include: vec!["src/**".to_string()],
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        let lines: Vec<&str> = value.as_str().unwrap().lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"type":"file","path":"src/lib.rs","status":"modified","hunk_count":1}"#,
+                r#"{"type":"summary","files":1,"hunks":1}"#,
+            ]
+        );
+
+        let options = ListOptions {
+            mode: ListMode::SpecTemplate,
+            ..options
+        };
+        assert!(list_value(&mut session, &options).is_err());
+    }
+
+    #[test]
+    fn stat_counts_lines_per_hunk_file_group_and_total() {
+        let mut session = session(repo());
+        let options = ListOptions {
+            mode: ListMode::Stat,
+            group: ListGrouping::Status,
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert_eq!(
+            value["total"],
+            serde_json::json!({"files": 3, "hunks": 3, "added": 2, "removed": 2})
+        );
+        let modified = &value["groups"][2];
+        assert_eq!(modified["name"], "modified");
+        assert_eq!(modified["added"], 1);
+        assert_eq!(modified["files"][0]["removed"], 1);
+        assert_eq!(modified["files"][0]["hunks"][0]["added"], 1);
+
+        let options = ListOptions {
+            format: ListFormat::Text,
+            group: ListGrouping::None,
+            color: ColorChoice::Never,
+            ..options
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        let text = value.as_str().unwrap();
+        assert!(text.contains("A docs/new.md | 1 +\n"));
+        assert!(text.contains("D old.txt     | 1 -\n"));
+        assert!(text.contains("M src/lib.rs  | 2 +-\n    hunk 0 replace "));
+        assert!(text.ends_with("3 files, 3 hunks, +2 -2\n"));
+
+        let options = ListOptions {
+            format: ListFormat::Ndjson,
+            ..options
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert!(value.as_str().unwrap().ends_with(
+            "{\"type\":\"summary\",\"files\":3,\"hunks\":3,\"added\":2,\"removed\":2}\n"
+        ));
+
+        let options = ListOptions {
+            format: ListFormat::Patch,
+            ..options
+        };
+        assert!(list_value(&mut session, &options).is_err());
+    }
+
+    #[test]
+    fn pages_resume_from_the_cursor_at_file_and_hunk_granularity() {
+        let backend = MemoryBackend::new()
+            .with_revision(
+                "base",
+                None,
+                &[
+                    ("a.txt", "1\n2\n3\n4\n5\n"),
+                    ("b.txt", "b\n"),
+                    ("c.txt", "c\n"),
+                ],
+            )
+            .with_revision(
+                "@",
+                Some("base"),
+                &[
+                    ("a.txt", "X\n2\nY\n4\nZ\n"),
+                    ("b.txt", "B\n"),
+                    ("c.txt", "C\n"),
+                ],
+            );
+        let mut session = session(backend);
+
+        let options = ListOptions {
+            mode: ListMode::Files,
+            limit: Some(2),
+            ..ListOptions::default()
+        };
+        let first = list_value(&mut session, &options).unwrap();
+        assert_eq!(listed_paths(&first), ["a.txt", "b.txt"]);
+        let options = ListOptions {
+            cursor: Some(first["next_cursor"].as_str().unwrap().to_string()),
+            ..options
+        };
+        let second = list_value(&mut session, &options).unwrap();
+        assert_eq!(listed_paths(&second), ["c.txt"]);
+        assert!(second.get("next_cursor").is_none());
+
+        // Hunk pages split a.txt; each page holds what fits the byte budget.
+        let mut options = ListOptions {
+            page_by: PageUnit::Hunk,
+            max_output_bytes: Some(1),
+            ..ListOptions::default()
+        };
+        let mut pages = Vec::new();
+        loop {
+            let page = list_value(&mut session, &options).unwrap();
+            let files = page["files"].as_array().unwrap();
+            assert_eq!(files.len(), 1);
+            pages.push(format!(
+                "{}#{}",
+                files[0]["path"].as_str().unwrap(),
+                files[0]["hunks"][0]["index"]
+            ));
+            match page["next_cursor"].as_str() {
+                Some(cursor) => options.cursor = Some(cursor.to_string()),
+                None => break,
+            }
+        }
+        assert_eq!(
+            pages,
+            ["a.txt#0", "a.txt#1", "a.txt#2", "b.txt#0", "c.txt#0"]
+        );
+
+        let options = ListOptions {
+            offset: 4,
+            page_by: PageUnit::Hunk,
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert_eq!(listed_paths(&value), ["c.txt"]);
+
+        let options = ListOptions {
+            cursor: Some("nonsense".to_string()),
+            ..ListOptions::default()
+        };
+        assert!(list_value(&mut session, &options).is_err());
+        let options = ListOptions {
+            cursor: Some(
+                Cursor {
+                    path: "gone.txt".to_string(),
+                    hunk: 0,
+                }
+                .encode(),
+            ),
+            ..ListOptions::default()
+        };
+        let err = list_value(&mut session, &options).unwrap_err().to_string();
+        assert!(err.contains("gone.txt"));
+    }
+
+    #[test]
+    fn truncation_cuts_payloads_but_keeps_ids() {
+        let backend = MemoryBackend::new()
+            .with_revision("base", None, &[("f.txt", "keep\nold\n")])
+            .with_revision(
+                "@",
+                Some("base"),
+                &[("f.txt", "keep\nnew 1\nnew 2\nnew 3\n")],
+            );
+        let mut session = session(backend);
+        let full = list_value(&mut session, &ListOptions::default()).unwrap();
+
+        let options = ListOptions {
+            max_lines: Some(1),
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        let hunk = &value["files"][0]["hunks"][0];
+        assert_eq!(hunk["id"], full["files"][0]["hunks"][0]["id"]);
+        assert_eq!(hunk["added"], "new 1\n");
+        assert_eq!(hunk["after"]["lines"], 3);
+        assert_eq!(hunk["truncated"], true);
+        assert_eq!(value["files"][0]["truncated"], true);
+
+        let options = ListOptions {
+            max_bytes: Some(100),
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert!(value["files"][0]["hunks"][0].get("truncated").is_none());
+
+        let options = ListOptions {
+            format: ListFormat::Patch,
+            ..options
+        };
+        assert!(list_value(&mut session, &options).is_err());
+    }
+
+    #[test]
+    fn hunk_filters_and_sorting_narrow_the_listing_and_spec_template() {
+        let backend = MemoryBackend::new()
+            .with_revision(
+                "base",
+                None,
+                &[
+                    ("a.txt", "a\n"),
+                    ("b.txt", "b\n"),
+                    ("c.txt", "1\n2\n3\n4\n5\n"),
+                ],
+            )
+            .with_revision(
+                "@",
+                Some("base"),
+                &[
+                    ("a.txt", "A\n"),
+                    ("b.txt", "b\nfoo 1\nfoo 2\nfoo 3\n"),
+                    ("c.txt", "2\n3\n4\nfive\n"),
+                ],
+            );
+        let mut session = session(backend);
+
+        let options = ListOptions {
+            sort: Some(ListSort::Size),
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert_eq!(listed_paths(&value), ["b.txt", "c.txt", "a.txt"]);
+
+        let options = ListOptions {
+            types: vec![HunkKind::Delete, HunkKind::Insert],
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert_eq!(listed_paths(&value), ["b.txt", "c.txt"]);
+        assert_eq!(value["files"][1]["hunks"][0]["type"], "delete");
+        assert_eq!(value["files"][1]["hunks"].as_array().unwrap().len(), 1);
+
+        let options = ListOptions {
+            min_lines: Some(2),
+            max_lines_changed: Some(2),
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert_eq!(listed_paths(&value), ["a.txt", "c.txt"]);
+        assert_eq!(value["files"][1]["hunks"][0]["index"], 1);
+
+        let options = ListOptions {
+            grep: Some("^foo [0-9]".to_string()),
+            mode: ListMode::SpecTemplate,
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        let files = value["files"].as_object().unwrap();
+        assert_eq!(files.keys().collect::<Vec<_>>(), ["b.txt"]);
+
+        let options = ListOptions {
+            grep: Some("(".to_string()),
+            ..ListOptions::default()
+        };
+        assert!(list_value(&mut session, &options).is_err());
+    }
+
+    #[test]
+    fn patch_format_renders_the_spec_preview() {
+        let mut session = session(repo());
+        let options = ListOptions {
+            format: ListFormat::Patch,
+            spec: Some(r#"{"files": {"src/lib.rs": {"hunks": [0]}}, "default": "reset"}"#.into()),
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        let patch = value.as_str().unwrap();
+
+        let id = &get_hunks("one\ntwo\nthree\n", "one\nTWO\nthree\n")[0].id;
+        assert_eq!(
+            patch,
+            format!(
+                "diff --git a/src/lib.rs b/src/lib.rs\n--- a/src/lib.rs\n+++ b/src/lib.rs\n\
+                 @@ -1,3 +1,3 @@ {id}\n one\n-two\n+TWO\n three\n"
+            )
+        );
+
+        let options = ListOptions {
+            mode: ListMode::Files,
+            ..options
+        };
+        assert!(list_value(&mut session, &options).is_err());
+    }
+
+    #[test]
+    fn patch_selection_matches_hunks_by_id_or_content() {
+        let backend = MemoryBackend::new()
+            .with_revision("base", None, &[("f.txt", "1\n2\n3\n4\n5\n6\n7\n8\n")])
+            .with_revision(
+                "@",
+                Some("base"),
+                &[("f.txt", "1\nTWO\n3\n4\n5\nSIX\n7\n8\n")],
+            );
+        let mut session = session(backend);
+        let hunks = get_hunks("1\n2\n3\n4\n5\n6\n7\n8\n", "1\nTWO\n3\n4\n5\nSIX\n7\n8\n");
+        let dir = std::env::temp_dir().join(format!("jj-hunk-unit-patch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let patch_path = dir.join("selection.patch");
+        let spec_for = |session: &mut Session, patch: &str| {
+            fs::write(&patch_path, patch).unwrap();
+            spec_from_patch(session, patch_path.to_str().unwrap(), None)
+                .map(|spec| serde_json::from_str::<serde_json::Value>(&spec).unwrap())
+        };
+
+        // The second hunk, by its trailer id.
+        let spec = spec_for(
+            &mut session,
+            &format!(
+                "diff --git a/f.txt b/f.txt\n--- a/f.txt\n+++ b/f.txt\n\
+                 @@ -6,3 +6,3 @@ {}\n-6\n+SIX\n 7\n 8\n",
+                hunks[1].id
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            spec["files"]["f.txt"]["ids"],
+            serde_json::json!([hunks[1].id])
+        );
+        assert_eq!(spec["default"], "reset");
+
+        // The first hunk, by content, from a patch without ids.
+        let spec = spec_for(
+            &mut session,
+            "--- a/f.txt\n+++ b/f.txt\n@@ -1,3 +1,3 @@\n 1\n-2\n+TWO\n 3\n",
+        )
+        .unwrap();
+        assert_eq!(
+            spec["files"]["f.txt"]["ids"],
+            serde_json::json!([hunks[0].id])
+        );
+
+        // A hand-edited hunk corresponds to nothing.
+        let err = spec_for(
+            &mut session,
+            "--- a/f.txt\n+++ b/f.txt\n@@ -1,3 +1,3 @@\n 1\n-2\n+Two\n 3\n",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("does not match any current hunk"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn markdown_renders_groups_captions_and_fenced_diffs() {
+        let mut session = session(repo());
+        let options = ListOptions {
+            format: ListFormat::Markdown,
+            group: ListGrouping::Directory,
+            include: vec!["src/**".to_string(), "docs/**".to_string()],
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        let markdown = value.as_str().unwrap();
+
+        let id = &get_hunks("one\ntwo\nthree\n", "one\nTWO\nthree\n")[0].id;
+        assert!(markdown.starts_with("## docs\n\n### `docs/new.md` (added)\n"));
+        assert!(markdown.contains("```diff markdown\n+hi\n```\n"));
+        assert!(markdown.ends_with(&format!(
+            "## src\n\n### `src/lib.rs` (modified)\n\n\
+             Hunk 0 · `{id}` · replace · `-2,1 +2,1`\n\n\
+             ```diff rust\n one\n-two\n+TWO\n three\n```\n"
+        )));
+        assert_eq!(markdown_fence(&["+```".to_string()]), "````");
+    }
+
+    #[test]
+    fn markdown_clips_context_between_adjacent_hunks() {
+        let mut session = session(
+            MemoryBackend::new()
+                .with_revision("base", None, &[("notes.txt", "a\nb\nc\nd\ne\n")])
+                .with_revision("@", Some("base"), &[("notes.txt", "a\nB\nc\nD\ne\n")]),
+        );
+        let options = ListOptions {
+            format: ListFormat::Markdown,
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        let markdown = value.as_str().unwrap();
+
+        assert!(markdown.contains("```diff\n a\n-b\n+B\n c\n```\n"));
+        assert!(markdown.contains("```diff\n c\n-d\n+D\n e\n```\n"));
+    }
+
+    #[test]
+    fn html_page_lists_each_file_and_hunk() {
+        let mut session = session(repo());
+        let options = ListOptions {
+            format: ListFormat::Html,
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        let page = value.as_str().unwrap();
+
+        assert!(page.contains("<title>jj-hunk review of @</title>"));
+        for path in ["docs/new.md", "old.txt", "src/lib.rs"] {
+            assert!(page.contains(&format!("data-path=\"{path}\"")));
+        }
+        assert_eq!(page.matches("class=\"hunk-toggle\"").count(), 3);
+    }
+
+    #[test]
+    fn text_output_numbers_lines_and_colors_on_request() {
+        let mut session = session(repo());
+        let options = ListOptions {
+            format: ListFormat::Text,
+            include: vec!["src/**".to_string()],
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        let id = &get_hunks("one\ntwo\nthree\n", "one\nTWO\nthree\n")[0].id;
+        assert_eq!(
+            value.as_str().unwrap(),
+            format!(
+                "M src/lib.rs\n  hunk 0 replace {id} (before 2+1 after 2+1)\n\
+                 \x20   1 1   one\n    2   - two\n      2 + TWO\n    3 3   three\n"
+            )
+        );
+
+        let options = ListOptions {
+            color: ColorChoice::Always,
+            ..options
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert!(value.as_str().unwrap().contains("\x1b[31m- two\x1b[0m"));
+        assert!(!Palette::new(ColorChoice::Auto, false).color);
+        assert!(!Palette::new(ColorChoice::Never, true).color);
+    }
+
+    #[test]
+    fn side_by_side_pairs_and_wraps_lines() {
+        let backend = MemoryBackend::new()
+            .with_revision("base", None, &[("f.txt", "keep\nold words here\n")])
+            .with_revision(
+                "@",
+                Some("base"),
+                &[("f.txt", "keep\nnew words here too\n")],
+            );
+        let mut session = session(backend);
+        let options = ListOptions {
+            format: ListFormat::Text,
+            side_by_side: true,
+            width: Some(40),
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        let lines: Vec<&str> = value.as_str().unwrap().lines().skip(2).collect();
+        assert_eq!(
+            lines,
+            [
+                "    1   keep         │ 1   keep",
+                "    2 - old words he │ 2 + new words he",
+                "        re           │     re too",
+            ]
+        );
+
+        let options = ListOptions {
+            color: ColorChoice::Always,
+            ..options
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert!(value.as_str().unwrap().contains("\x1b[1;31mold\x1b[0m"));
+
+        let options = ListOptions {
+            format: ListFormat::Json,
+            ..options
+        };
+        assert!(list_value(&mut session, &options).is_err());
+    }
+
+    #[test]
+    fn template_renders_each_file_or_hunk() {
+        let mut session = session(repo());
+        let options = ListOptions {
+            template: Some("{status}\\t{path}\\n".to_string()),
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert_eq!(
+            value.as_str().unwrap(),
+            "added\tdocs/new.md\nremoved\told.txt\nmodified\tsrc/lib.rs\n"
+        );
+
+        let options = ListOptions {
+            template: Some("{group}:{path}#{hunk.index} {hunk.type}\\n".to_string()),
+            group: ListGrouping::Extension,
+            include: vec!["src/**".to_string()],
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert_eq!(value.as_str().unwrap(), "rs:src/lib.rs#0 replace\n");
+
+        let options = ListOptions {
+            mode: ListMode::Files,
+            ..options
+        };
+        assert!(list_value(&mut session, &options).is_err());
+
+        // A template naming an existing file is still literal text.
+        let file = std::env::temp_dir().join(format!("jj-hunk-unit-{}.tmpl", std::process::id()));
+        fs::write(&file, "{path}\n").unwrap();
+        let path = file.to_str().unwrap().to_string();
+        let options = ListOptions {
+            template: Some(path.clone()),
+            include: vec!["src/**".to_string()],
+            ..ListOptions::default()
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert_eq!(value.as_str().unwrap(), path);
+
+        let options = ListOptions {
+            template: None,
+            template_file: Some(path),
+            ..options
+        };
+        let value = list_value(&mut session, &options).unwrap();
+        assert_eq!(value.as_str().unwrap(), "src/lib.rs\n");
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn file_differs_compares_presence_size_and_content() {
+        let root =
+            std::env::temp_dir().join(format!("jj-hunk-unit-differs-{}", std::process::id()));
+        let (left, right) = (root.join("left"), root.join("right"));
+        fs::create_dir_all(&left).unwrap();
+        fs::create_dir_all(&right).unwrap();
+        for (name, before, after) in [
+            ("same.txt", "abc\n", "abc\n"),
+            ("longer.txt", "abc\n", "abcd\n"),
+            ("edited.txt", "abc\n", "xyz\n"),
+        ] {
+            fs::write(left.join(name), before).unwrap();
+            fs::write(right.join(name), after).unwrap();
+        }
+        fs::write(right.join("added.txt"), "new\n").unwrap();
+
+        assert!(!file_differs(&left, &right, "same.txt"));
+        assert!(file_differs(&left, &right, "longer.txt"));
+        assert!(file_differs(&left, &right, "edited.txt"));
+        assert!(file_differs(&left, &right, "added.txt"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn select_only_touches_the_changed_paths() {
+        let root = std::env::temp_dir().join(format!("jj-hunk-unit-select-{}", std::process::id()));
+        let (left, right) = (root.join("left"), root.join("right"));
+        fs::create_dir_all(&left).unwrap();
+        fs::create_dir_all(&right).unwrap();
+        for name in ["listed.txt", "unlisted.txt"] {
+            fs::write(left.join(name), "old\n").unwrap();
+            fs::write(right.join(name), "new\n").unwrap();
+        }
+        fs::write(right.join("added.txt"), "new\n").unwrap();
+        let spec = Spec::from_str(r#"{"files": {}, "default": "reset"}"#).unwrap();
+        let cache = HunkCache::new(None);
+
+        let changed = vec!["listed.txt".to_string(), "added.txt".to_string()];
+        select_dirs(&spec, &cache, &left, &right, Some(changed)).unwrap();
+        assert_eq!(
+            fs::read_to_string(right.join("listed.txt")).unwrap(),
+            "old\n"
+        );
+        assert!(!right.join("added.txt").exists());
+        assert_eq!(
+            fs::read_to_string(right.join("unlisted.txt")).unwrap(),
+            "new\n"
+        );
+
+        select_dirs(&spec, &cache, &left, &right, None).unwrap();
+        assert_eq!(
+            fs::read_to_string(right.join("unlisted.txt")).unwrap(),
+            "old\n"
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn check_report_flags_missing_files_and_unknown_ids() {
+        let mut session = session(repo());
+        let spec = r#"{"files": {"src/lib.rs": {"hunks": [0, 3]}, "gone.rs": {"action": "keep"}}}"#;
+        let report = check_report(&mut session, Some(spec), None, None).unwrap();
+
+        assert!(!report.is_ok());
+        assert_eq!(report.files[0].path, "gone.rs");
+        assert!(report.files[0].missing);
+        assert_eq!(report.files[1].path, "src/lib.rs");
+        assert_eq!(report.files[1].selected, 1);
+        assert_eq!(report.files[1].invalid_indices, [3]);
+    }
+
+    #[test]
+    fn persistent_session_only_caches_the_last_commit_pair() {
+        let backend = repo().with_revision(
+            "next",
+            Some("@"),
+            &[("src/lib.rs", "one\nTWO\nthree\nfour\n")],
+        );
+        let mut session = Session {
+            persistent: true,
+            ..session(backend)
+        };
+
+        list_value(&mut session, &ListOptions::default()).unwrap();
+        assert!(session.entries.keys().all(|key| key.after == "@"));
+        assert!(!session.contents.is_empty());
+
+        list_value(&mut session, &ListOptions::from(Some("next"))).unwrap();
+        assert!(!session.entries.is_empty());
+        assert!(session
+            .entries
+            .keys()
+            .all(|key| key.before == "@" && key.after == "next"));
+        assert!(session
+            .contents
+            .keys()
+            .all(|(commit, _)| commit == "@" || commit == "next"));
+    }
+
+    #[test]
+    fn fuzzy_spec_template_lists_hunk_objects_with_context() {
+        let mut session = session(repo());
+        let options = ListOptions {
+            mode: ListMode::SpecTemplate,
+            fuzzy: true,
+            ..ListOptions::default()
+        };
+        let template = list_value(&mut session, &options).unwrap();
+        assert_eq!(template["fuzzy"], true);
+        assert!(template.get("expect").is_none());
+
+        let hunk = &template["files"]["src/lib.rs"]["hunks"][0];
+        assert_eq!(hunk["removed"], "two\n");
+        assert_eq!(hunk["added"], "TWO\n");
+        assert_eq!(hunk["context"]["pre"], "one\n");
+
+        // The template parses back into a spec selecting the same hunks.
+        let spec = Spec::from_str(&template.to_string()).unwrap();
+        assert!(spec.fuzzy);
+        let preview = list_value(
+            &mut session,
+            &ListOptions {
+                spec: Some(template.to_string()),
+                ..ListOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            listed_paths(&preview),
+            ["docs/new.md", "old.txt", "src/lib.rs"]
+        );
+
+        let err = list_value(
+            &mut session,
+            &ListOptions {
+                fuzzy: true,
+                ..ListOptions::default()
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("--spec-template"), "{err}");
+    }
+
+    #[test]
+    fn spec_template_expectation_survives_trimming_and_catches_new_trees() {
+        let mut session = session(repo());
+        let options = ListOptions {
+            mode: ListMode::SpecTemplate,
+            ..ListOptions::default()
+        };
+        let mut template = list_value(&mut session, &options).unwrap();
+        let expect = &template["expect"];
+        assert_eq!(expect["commit_id"], "@");
+        assert_eq!(expect["operation_id"], "op0");
+        assert!(expect["tree_id"].as_str().unwrap().starts_with("sha256:"));
+        assert_eq!(
+            expect["paths"],
+            serde_json::json!(["docs/new.md", "old.txt", "src/lib.rs"])
+        );
+
+        // Dropping entries and relying on `default: reset` keeps the spec valid.
+        template["files"].as_object_mut().unwrap().remove("old.txt");
+        let spec = Spec::from_str(&template.to_string()).unwrap();
+        verify_expectation(&mut session, &spec, None).unwrap();
+
+        let edited = MemoryBackend::new()
+            .with_revision("base", None, &[("src/lib.rs", "one\ntwo\nthree\n")])
+            .with_revision("@", Some("base"), &[("src/lib.rs", "one\nTWO\n3\n")]);
+        let err = verify_expectation(&mut self::session(edited), &spec, None).unwrap_err();
+        assert!(err.to_string().contains("to have tree"), "{err}");
+    }
+
+    #[test]
+    fn spec_template_listed_with_binary_hunks_verifies() {
+        let mut session = session(
+            MemoryBackend::new()
+                .with_revision("base", None, &[("blob.bin", "a\0b\n")])
+                .with_revision("@", Some("base"), &[("blob.bin", "a\0c\n")]),
+        );
+        let options = ListOptions {
+            mode: ListMode::SpecTemplate,
+            binary: BinaryMode::Include,
+            ..ListOptions::default()
+        };
+        let template = list_value(&mut session, &options).unwrap();
+        assert_eq!(template["expect"]["paths"], serde_json::json!(["blob.bin"]));
+
+        let spec = Spec::from_str(&template.to_string()).unwrap();
+        verify_expectation(&mut session, &spec, None).unwrap();
+    }
+
+    #[test]
+    fn spec_template_from_narrowed_list_verifies_the_listed_paths() {
+        let mut session = session(repo());
+        let options = ListOptions {
+            mode: ListMode::SpecTemplate,
+            paths: vec!["glob:**/*.rs".to_string()],
+            ..ListOptions::default()
+        };
+        let template = list_value(&mut session, &options).unwrap();
+        assert_eq!(
+            template["expect"]["paths"],
+            serde_json::json!(["src/lib.rs"])
+        );
+
+        let spec = template.to_string();
+        let report = check_report(&mut session, Some(&spec), None, None).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.files.len(), 1);
+    }
+
+    #[test]
+    fn split_checks_expectation_then_runs_selection_and_records_undo() {
+        let state_dir =
+            std::env::temp_dir().join(format!("jj-hunk-unit-undo-{}", std::process::id()));
+        let backend = repo().with_state_dir(&state_dir);
+        let runs = backend.runs();
+        let mut session = session(backend);
+
+        let stale = r#"{"files": {}, "expect": {"commit_id": "other"}}"#;
+        let err = split(&mut session, Some(stale), None, "msg", None).unwrap_err();
+        assert!(err.to_string().contains("re-list the revision"), "{err}");
+        assert!(runs.lock().unwrap().is_empty());
+
+        let template = list_value(
+            &mut session,
+            &ListOptions {
+                mode: ListMode::SpecTemplate,
+                ..ListOptions::default()
+            },
+        )
+        .unwrap();
+        let spec = template.to_string();
+        split(&mut session, Some(&spec), None, "msg", Some("@")).unwrap();
+        assert_eq!(runs.lock().unwrap().len(), 1);
+        assert_eq!(runs.lock().unwrap()[0].command, "split");
+        assert_eq!(runs.lock().unwrap()[0].rev.as_deref(), Some("@"));
+        assert_eq!(session.backend.operation_id().unwrap(), "op1");
+
+        undo(&mut session, false).unwrap();
+        assert_eq!(session.backend.operation_id().unwrap(), "op0");
+        fs::remove_dir_all(&state_dir).unwrap();
     }
-    run_jj_with_selection(&args, spec, spec_file)
 }
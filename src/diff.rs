@@ -1,4 +1,5 @@
-use serde::Serialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use similar::{ChangeTag, TextDiff};
 use std::collections::HashSet;
@@ -7,14 +8,14 @@ use std::fmt::Write;
 pub const HUNK_ID_PREFIX: &str = "hunk-";
 const CONTEXT_LINES: usize = 3;
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LineRange {
     pub start: usize,
     #[serde(rename = "lines")]
     pub length: usize,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct HunkContext {
     #[serde(rename = "pre")]
     pub before: String,
@@ -22,7 +23,7 @@ pub struct HunkContext {
     pub after: String,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Hunk {
     pub index: usize,
     pub id: String,
@@ -34,16 +35,169 @@ pub struct Hunk {
     pub before_range: LineRange,
     #[serde(rename = "after")]
     pub after_range: LineRange,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub context: Option<HunkContext>,
+    /// Set when `removed`/`added` were cut short for display; `id` and the
+    /// ranges still describe the whole hunk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<bool>,
+}
+
+/// Which side of a diff a displayed line belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineKind {
+    Context,
+    Removed,
+    Added,
+}
+
+/// One displayed line of a hunk, numbered on the sides it appears on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HunkLine<'a> {
+    pub kind: LineKind,
+    /// The line without its line ending.
+    pub text: &'a str,
+    pub before: Option<usize>,
+    pub after: Option<usize>,
+}
+
+impl Hunk {
+    /// The removed and added lines between their context lines.
+    ///
+    /// Context stops at `previous` and `next`, the neighbouring hunks of the
+    /// same file, so it never shows lines they change as unchanged.
+    pub fn lines(&self, previous: Option<&Hunk>, next: Option<&Hunk>) -> Vec<HunkLine<'_>> {
+        let start = self.before_range.start;
+        let end = start + self.before_range.length;
+        let pre_limit = previous.map_or(usize::MAX, |previous| {
+            start.saturating_sub(previous.before_range.start + previous.before_range.length)
+        });
+        let post_limit = next.map_or(usize::MAX, |next| {
+            next.before_range.start.saturating_sub(end)
+        });
+        let (pre, post) = match &self.context {
+            Some(context) => (
+                last_lines(&context.before, pre_limit),
+                first_lines(&context.after, post_limit),
+            ),
+            None => ("", ""),
+        };
+        let pre_count = pre.split_inclusive('\n').count();
+        let mut before = self.before_range.start.saturating_sub(pre_count);
+        let mut after = self.after_range.start.saturating_sub(pre_count);
+
+        [
+            (LineKind::Context, pre),
+            (LineKind::Removed, self.removed.as_str()),
+            (LineKind::Added, self.added.as_str()),
+            (LineKind::Context, post),
+        ]
+        .into_iter()
+        .flat_map(|(kind, text)| text.split_inclusive('\n').map(move |line| (kind, line)))
+        .map(|(kind, text)| {
+            let line = HunkLine {
+                kind,
+                text: text.trim_end_matches(['\n', '\r']),
+                before: (kind != LineKind::Added).then_some(before),
+                after: (kind != LineKind::Removed).then_some(after),
+            };
+            before += usize::from(kind != LineKind::Added);
+            after += usize::from(kind != LineKind::Removed);
+            line
+        })
+        .collect()
+    }
+}
+
+/// The lines of each of a file's hunks, with context clipped between them.
+pub fn file_lines(hunks: &[Hunk]) -> Vec<Vec<HunkLine<'_>>> {
+    hunks
+        .iter()
+        .enumerate()
+        .map(|(position, hunk)| {
+            let previous = position.checked_sub(1).map(|previous| &hunks[previous]);
+            hunk.lines(previous, hunks.get(position + 1))
+        })
+        .collect()
+}
+
+/// The first `limit` lines of `text`.
+fn first_lines(text: &str, limit: usize) -> &str {
+    let end = text.split_inclusive('\n').take(limit).map(str::len).sum();
+    &text[..end]
+}
+
+/// The last `limit` lines of `text`.
+fn last_lines(text: &str, limit: usize) -> &str {
+    let skip = text.split_inclusive('\n').count().saturating_sub(limit);
+    let start = text.split_inclusive('\n').take(skip).map(str::len).sum();
+    &text[start..]
+}
+
+/// Pair the lines of a hunk into rows: context lines face themselves and
+/// removed lines face the added lines that replaced them.
+pub fn side_by_side<'a, 'b>(
+    lines: &'b [HunkLine<'a>],
+) -> Vec<(Option<&'b HunkLine<'a>>, Option<&'b HunkLine<'a>>)> {
+    let mut rows = Vec::new();
+    let mut rest = lines;
+    while let Some(line) = rest.first() {
+        if line.kind == LineKind::Context {
+            rows.push((Some(line), Some(line)));
+            rest = &rest[1..];
+            continue;
+        }
+        let removed = rest
+            .iter()
+            .take_while(|line| line.kind == LineKind::Removed)
+            .count();
+        let added = rest[removed..]
+            .iter()
+            .take_while(|line| line.kind == LineKind::Added)
+            .count();
+        for row in 0..removed.max(added) {
+            rows.push((
+                (row < removed).then(|| &rest[row]),
+                (row < added).then(|| &rest[removed + row]),
+            ));
+        }
+        rest = &rest[removed + added..];
+    }
+    rows
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct HunkSelection {
     pub indices: HashSet<usize>,
     pub ids: HashSet<String>,
+    /// Content of id-selected hunks, used to relocate ids that went stale.
+    pub patterns: Vec<HunkPattern>,
+}
+
+/// A hunk as it was listed, carried in a spec so it can be found again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HunkPattern {
+    pub id: String,
+    pub removed: String,
+    pub added: String,
+    pub context: Option<HunkContext>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Relocation {
+    /// A stale id was matched to a hunk of the current diff.
+    Moved {
+        from: String,
+        to: String,
+        index: usize,
+    },
+    /// A stale id could not be matched unambiguously.
+    Missing { id: String },
 }
 
+/// Fraction of a pattern's context lines that must still match.
+const RELOCATION_THRESHOLD: f64 = 0.5;
+
 impl HunkSelection {
     pub fn is_empty(&self) -> bool {
         self.indices.is_empty() && self.ids.is_empty()
@@ -52,223 +206,284 @@ impl HunkSelection {
     pub fn matches(&self, index: usize, id: &str) -> bool {
         self.indices.contains(&index) || self.ids.contains(id)
     }
-}
 
-/// Extract hunks from before/after content
-pub fn get_hunks(before: &str, after: &str) -> Vec<Hunk> {
-    let diff = TextDiff::from_lines(before, after);
-    let before_lines = split_lines_with_endings(before);
-    let mut hunks = Vec::new();
-    let mut current_removed = String::new();
-    let mut current_added = String::new();
-    let mut in_hunk = false;
-    let mut before_line = 1;
-    let mut after_line = 1;
-    let mut hunk_before_start = 0;
-    let mut hunk_after_start = 0;
-    let mut hunk_before_len = 0;
-    let mut hunk_after_len = 0;
-
-    for change in diff.iter_all_changes() {
-        let line_count = count_lines(change.value());
-        match change.tag() {
-            ChangeTag::Equal => {
-                if in_hunk {
-                    finalize_hunk(
-                        &mut hunks,
-                        &mut current_removed,
-                        &mut current_added,
-                        &before_lines,
-                        hunk_before_start,
-                        hunk_after_start,
-                        hunk_before_len,
-                        hunk_after_len,
-                    );
-                    hunk_before_len = 0;
-                    hunk_after_len = 0;
-                    in_hunk = false;
-                }
-                before_line += line_count;
-                after_line += line_count;
-            }
-            ChangeTag::Delete => {
-                if !in_hunk {
-                    in_hunk = true;
-                    hunk_before_start = before_line;
-                    hunk_after_start = after_line;
-                    hunk_before_len = 0;
-                    hunk_after_len = 0;
+    /// Re-point selected ids that no longer exist in `hunks`.
+    ///
+    /// A stale id is relocated to the hunk with identical removed/added text
+    /// whose context best matches the listed context. Ids without a pattern
+    /// or context, or whose best match is ambiguous, are reported as missing.
+    pub fn relocate(&mut self, hunks: &[Hunk]) -> Vec<Relocation> {
+        let current: HashSet<&str> = hunks.iter().map(|hunk| hunk.id.as_str()).collect();
+        let mut stale: Vec<String> = self
+            .ids
+            .iter()
+            .filter(|id| !current.contains(id.as_str()))
+            .cloned()
+            .collect();
+        stale.sort();
+
+        let mut relocations = Vec::new();
+        for id in stale {
+            let target = self
+                .patterns
+                .iter()
+                .find(|pattern| pattern.id == id)
+                .and_then(|pattern| find_relocation(pattern, hunks, &self.ids));
+
+            match target {
+                Some(hunk) => {
+                    self.ids.remove(&id);
+                    self.ids.insert(hunk.id.clone());
+                    relocations.push(Relocation::Moved {
+                        from: id,
+                        to: hunk.id.clone(),
+                        index: hunk.index,
+                    });
                 }
-                current_removed.push_str(change.value());
-                hunk_before_len += line_count;
-                before_line += line_count;
+                None => relocations.push(Relocation::Missing { id }),
             }
-            ChangeTag::Insert => {
-                if !in_hunk {
-                    in_hunk = true;
-                    hunk_before_start = before_line;
-                    hunk_after_start = after_line;
-                    hunk_before_len = 0;
-                    hunk_after_len = 0;
-                }
-                current_added.push_str(change.value());
-                hunk_after_len += line_count;
-                after_line += line_count;
+        }
+
+        relocations
+    }
+}
+
+fn find_relocation<'a>(
+    pattern: &HunkPattern,
+    hunks: &'a [Hunk],
+    selected: &HashSet<String>,
+) -> Option<&'a Hunk> {
+    // Without context, identical edits elsewhere in the file would match too.
+    let context = pattern.context.as_ref()?;
+    if context.before.is_empty() && context.after.is_empty() {
+        return None;
+    }
+
+    let mut best: Option<(&Hunk, f64)> = None;
+    let mut tied = false;
+
+    for hunk in hunks {
+        if hunk.removed != pattern.removed
+            || hunk.added != pattern.added
+            || selected.contains(&hunk.id)
+        {
+            continue;
+        }
+
+        let score = context_similarity(context, hunk.context.as_ref());
+        match best {
+            Some((_, best_score)) if score < best_score => {}
+            Some((_, best_score)) if score == best_score => tied = true,
+            _ => {
+                best = Some((hunk, score));
+                tied = false;
             }
         }
     }
 
-    if in_hunk {
-        finalize_hunk(
-            &mut hunks,
-            &mut current_removed,
-            &mut current_added,
-            &before_lines,
-            hunk_before_start,
-            hunk_after_start,
-            hunk_before_len,
-            hunk_after_len,
-        );
+    match best {
+        Some((hunk, score)) if !tied && score >= RELOCATION_THRESHOLD => Some(hunk),
+        _ => None,
     }
+}
 
-    hunks
+/// Share of the expected context lines found next to the candidate hunk.
+fn context_similarity(expected: &HunkContext, actual: Option<&HunkContext>) -> f64 {
+    let empty = HunkContext {
+        before: String::new(),
+        after: String::new(),
+    };
+    let actual = actual.unwrap_or(&empty);
+
+    let expected_pre = split_lines_with_endings(&expected.before);
+    let actual_pre = split_lines_with_endings(&actual.before);
+    let expected_post = split_lines_with_endings(&expected.after);
+    let actual_post = split_lines_with_endings(&actual.after);
+
+    let total = expected_pre.len() + expected_post.len();
+    if total == 0 {
+        return 0.0;
+    }
+
+    // Pre-context is anchored at the hunk, so compare it from the end.
+    let pre_matches = expected_pre
+        .iter()
+        .rev()
+        .zip(actual_pre.iter().rev())
+        .filter(|(a, b)| a == b)
+        .count();
+    let post_matches = expected_post
+        .iter()
+        .zip(actual_post.iter())
+        .filter(|(a, b)| a == b)
+        .count();
+
+    (pre_matches + post_matches) as f64 / total as f64
 }
 
-fn finalize_hunk(
-    hunks: &mut Vec<Hunk>,
-    current_removed: &mut String,
-    current_added: &mut String,
-    before_lines: &[&str],
+/// The diff of one file: its hunks and the unchanged runs around them.
+///
+/// `equal[i]` is the unchanged text before `hunks[i]`; the last run follows
+/// the final hunk. Listing and applying both go through this model, so the
+/// indices and ids `list` reports are exactly the ones `select` matches.
+#[derive(Debug, Clone)]
+pub struct FileDiff {
+    hunks: Vec<Hunk>,
+    equal: Vec<String>,
+}
+
+/// A hunk whose lines are still being collected.
+struct PendingHunk {
     before_start: usize,
     after_start: usize,
     before_length: usize,
     after_length: usize,
-) {
-    let removed = std::mem::take(current_removed);
-    let added = std::mem::take(current_added);
-    let hunk_type = determine_hunk_type(&removed, &added);
-    let before_range = LineRange {
-        start: before_start,
-        length: before_length,
-    };
-    let after_range = LineRange {
-        start: after_start,
-        length: after_length,
-    };
-    let context = build_context(before_lines, &before_range);
-    let id = compute_hunk_id(hunk_type, &removed, &added, context.as_ref());
-
-    hunks.push(Hunk {
-        index: hunks.len(),
-        id,
-        hunk_type: hunk_type.to_string(),
-        removed,
-        added,
-        before_range,
-        after_range,
-        context,
-    });
+    removed: String,
+    added: String,
 }
 
-/// Apply only selected hunks, returning the result
-pub fn apply_selected_hunks(before: &str, after: &str, selected: &HunkSelection) -> String {
-    let diff = TextDiff::from_lines(before, after);
-    let before_lines = split_lines_with_endings(before);
-    let mut result = String::new();
-    let mut hunk_idx = 0;
-    let mut in_hunk = false;
-    let mut hunk_before = String::new();
-    let mut hunk_after = String::new();
-    let mut before_line = 1;
-    let mut hunk_before_start = 0;
-    let mut hunk_before_len = 0;
-
-    for change in diff.iter_all_changes() {
-        let line_count = count_lines(change.value());
-        match change.tag() {
-            ChangeTag::Equal => {
-                if in_hunk {
-                    apply_hunk_selection(
-                        &mut result,
-                        &mut hunk_before,
-                        &mut hunk_after,
-                        &before_lines,
-                        selected,
-                        hunk_idx,
-                        hunk_before_start,
-                        hunk_before_len,
-                    );
-                    hunk_idx += 1;
-                    hunk_before_len = 0;
-                    in_hunk = false;
+impl PendingHunk {
+    fn finish(self, index: usize, before_lines: &[&str]) -> Hunk {
+        let hunk_type = determine_hunk_type(&self.removed, &self.added);
+        let before_range = LineRange {
+            start: self.before_start,
+            length: self.before_length,
+        };
+        let after_range = LineRange {
+            start: self.after_start,
+            length: self.after_length,
+        };
+        let context = build_context(before_lines, &before_range);
+        let id = compute_hunk_id(hunk_type, &self.removed, &self.added, context.as_ref());
+
+        Hunk {
+            index,
+            id,
+            hunk_type: hunk_type.to_string(),
+            removed: self.removed,
+            added: self.added,
+            before_range,
+            after_range,
+            context,
+            truncated: None,
+        }
+    }
+}
+
+impl FileDiff {
+    /// Diff `before` against `after` in a single pass.
+    pub fn new(before: &str, after: &str) -> Self {
+        let diff = TextDiff::from_lines(before, after);
+        let before_lines = split_lines_with_endings(before);
+        let mut file = FileDiff {
+            hunks: Vec::new(),
+            equal: vec![String::new()],
+        };
+        let mut pending: Option<PendingHunk> = None;
+        let mut before_line = 1;
+        let mut after_line = 1;
+
+        for change in diff.iter_all_changes() {
+            let value = change.value();
+            let line_count = count_lines(value);
+            if change.tag() == ChangeTag::Equal {
+                if let Some(hunk) = pending.take() {
+                    file.push_hunk(hunk, &before_lines);
                 }
-                result.push_str(change.value());
+                file.equal
+                    .last_mut()
+                    .expect("there is always a trailing run")
+                    .push_str(value);
                 before_line += line_count;
+                after_line += line_count;
+                continue;
             }
-            ChangeTag::Delete => {
-                if !in_hunk {
-                    in_hunk = true;
-                    hunk_before_start = before_line;
-                    hunk_before_len = 0;
-                }
-                hunk_before.push_str(change.value());
-                hunk_before_len += line_count;
+
+            let hunk = pending.get_or_insert_with(|| PendingHunk {
+                before_start: before_line,
+                after_start: after_line,
+                before_length: 0,
+                after_length: 0,
+                removed: String::new(),
+                added: String::new(),
+            });
+            if change.tag() == ChangeTag::Delete {
+                hunk.removed.push_str(value);
+                hunk.before_length += line_count;
                 before_line += line_count;
+            } else {
+                hunk.added.push_str(value);
+                hunk.after_length += line_count;
+                after_line += line_count;
             }
-            ChangeTag::Insert => {
-                if !in_hunk {
-                    in_hunk = true;
-                    hunk_before_start = before_line;
-                    hunk_before_len = 0;
-                }
-                hunk_after.push_str(change.value());
+        }
+
+        if let Some(hunk) = pending.take() {
+            file.push_hunk(hunk, &before_lines);
+        }
+        file
+    }
+
+    /// Rebuild the diff of `before` from previously computed hunks.
+    ///
+    /// Returns `None` if the hunks do not line up with `before`.
+    pub fn from_hunks(before: &str, hunks: Vec<Hunk>) -> Option<Self> {
+        let before_lines = split_lines_with_endings(before);
+        let mut equal = Vec::with_capacity(hunks.len() + 1);
+        let mut cursor = 0;
+
+        for (index, hunk) in hunks.iter().enumerate() {
+            let start = hunk.before_range.start.checked_sub(1)?;
+            let end = start + hunk.before_range.length;
+            if hunk.index != index || start < cursor || end > before_lines.len() {
+                return None;
             }
+            if before_lines[start..end].concat() != hunk.removed {
+                return None;
+            }
+            equal.push(before_lines[cursor..start].concat());
+            cursor = end;
         }
+        equal.push(before_lines[cursor..].concat());
+
+        Some(FileDiff { hunks, equal })
     }
 
-    if in_hunk {
-        apply_hunk_selection(
-            &mut result,
-            &mut hunk_before,
-            &mut hunk_after,
-            &before_lines,
-            selected,
-            hunk_idx,
-            hunk_before_start,
-            hunk_before_len,
-        );
+    pub fn hunks(&self) -> &[Hunk] {
+        &self.hunks
     }
 
-    result
-}
+    pub fn into_hunks(self) -> Vec<Hunk> {
+        self.hunks
+    }
 
-fn apply_hunk_selection(
-    result: &mut String,
-    hunk_before: &mut String,
-    hunk_after: &mut String,
-    before_lines: &[&str],
-    selected: &HunkSelection,
-    hunk_idx: usize,
-    before_start: usize,
-    before_length: usize,
-) {
-    let removed = std::mem::take(hunk_before);
-    let added = std::mem::take(hunk_after);
-    let hunk_type = determine_hunk_type(&removed, &added);
-    let before_range = LineRange {
-        start: before_start,
-        length: before_length,
-    };
-    let context = build_context(before_lines, &before_range);
-    let id = compute_hunk_id(hunk_type, &removed, &added, context.as_ref());
+    /// Apply only the selected hunks to the `before` side.
+    pub fn apply(&self, selected: &HunkSelection) -> String {
+        let mut result = String::new();
+        for (equal, hunk) in self.equal.iter().zip(&self.hunks) {
+            result.push_str(equal);
+            if selected.matches(hunk.index, &hunk.id) {
+                result.push_str(&hunk.added);
+            } else {
+                result.push_str(&hunk.removed);
+            }
+        }
+        result.push_str(self.equal.last().expect("there is always a trailing run"));
+        result
+    }
 
-    if selected.matches(hunk_idx, &id) {
-        result.push_str(&added);
-    } else {
-        result.push_str(&removed);
+    fn push_hunk(&mut self, hunk: PendingHunk, before_lines: &[&str]) {
+        let index = self.hunks.len();
+        self.hunks.push(hunk.finish(index, before_lines));
+        self.equal.push(String::new());
     }
 }
 
+#[cfg(test)]
+pub fn get_hunks(before: &str, after: &str) -> Vec<Hunk> {
+    FileDiff::new(before, after).into_hunks()
+}
+
 fn determine_hunk_type(removed: &str, added: &str) -> &'static str {
     match (removed.is_empty(), added.is_empty()) {
         (true, false) => "insert",
@@ -301,6 +516,28 @@ fn compute_hunk_id(hunk_type: &str, removed: &str, added: &str, context: Option<
     format!("{HUNK_ID_PREFIX}{}", hex_encode(&digest))
 }
 
+/// Digest the hunk ids of a set of files, independent of file order.
+pub fn digest_hunk_ids<'a, I>(files: I) -> String
+where
+    I: IntoIterator<Item = (&'a str, &'a [Hunk])>,
+{
+    let mut files: Vec<_> = files.into_iter().collect();
+    files.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut hasher = Sha256::new();
+    for (path, hunks) in files {
+        hasher.update(path.as_bytes());
+        hasher.update(b"\0");
+        for hunk in hunks {
+            hasher.update(hunk.id.as_bytes());
+            hasher.update(b"\0");
+        }
+        hasher.update(b"\n");
+    }
+
+    format!("sha256:{}", hex_encode(&hasher.finalize()))
+}
+
 pub fn normalize_hunk_id(value: &str) -> Option<String> {
     let trimmed = value.trim();
     if trimmed.is_empty() {
@@ -321,7 +558,7 @@ pub fn normalize_hunk_id(value: &str) -> Option<String> {
     Some(format!("{HUNK_ID_PREFIX}{}", hex.to_lowercase()))
 }
 
-fn hex_encode(bytes: &[u8]) -> String {
+pub fn hex_encode(bytes: &[u8]) -> String {
     let mut out = String::with_capacity(bytes.len() * 2);
     for byte in bytes {
         let _ = write!(&mut out, "{:02x}", byte);
@@ -434,13 +671,137 @@ mod tests {
         let mut selection = HunkSelection::default();
         selection.ids.insert(hunks[0].id.clone());
 
-        let selected_result = apply_selected_hunks(before, after, &selection);
+        let selected_result = FileDiff::new(before, after).apply(&selection);
         assert_eq!(selected_result, after);
 
-        let empty_result = apply_selected_hunks(before, after, &HunkSelection::default());
+        let empty_result = FileDiff::new(before, after).apply(&HunkSelection::default());
         assert_eq!(empty_result, before);
     }
 
+    #[test]
+    fn file_diff_rebuilt_from_hunks_applies_identically() {
+        let before = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        let after = "new\na\nB\nc\nd\ne\nf\nh\ntail\n";
+        let diff = FileDiff::new(before, after);
+        assert_eq!(diff.hunks().len(), 4);
+
+        let rebuilt = FileDiff::from_hunks(before, diff.hunks().to_vec()).unwrap();
+        for index in 0..diff.hunks().len() {
+            let mut selection = HunkSelection::default();
+            selection.indices.insert(index);
+            assert_eq!(rebuilt.apply(&selection), diff.apply(&selection));
+        }
+        let mut all = HunkSelection::default();
+        all.indices.extend(0..diff.hunks().len());
+        assert_eq!(rebuilt.apply(&all), after);
+        assert_eq!(rebuilt.apply(&HunkSelection::default()), before);
+
+        assert!(FileDiff::from_hunks("x\ny\n", diff.into_hunks()).is_none());
+    }
+
+    #[test]
+    fn relocate_moves_stale_id_to_matching_hunk() {
+        let before = "a\nb\nc\nold\nd\ne\nf\n";
+        let after = "a\nb\nc\nnew\nd\ne\nf\n";
+        let listed = get_hunks(before, after).remove(0);
+
+        // The first context line changed, which invalidates the listed id.
+        let rebased_before = "A\nb\nc\nold\nd\ne\nf\n";
+        let rebased_after = "A\nb\nc\nnew\nd\ne\nf\n";
+        let hunks = get_hunks(rebased_before, rebased_after);
+        assert_ne!(hunks[0].id, listed.id);
+
+        let mut selection = HunkSelection::default();
+        selection.ids.insert(listed.id.clone());
+        selection.patterns.push(HunkPattern {
+            id: listed.id.clone(),
+            removed: listed.removed.clone(),
+            added: listed.added.clone(),
+            context: listed.context.clone(),
+        });
+
+        let relocations = selection.relocate(&hunks);
+        assert_eq!(
+            relocations,
+            vec![Relocation::Moved {
+                from: listed.id,
+                to: hunks[0].id.clone(),
+                index: 0,
+            }]
+        );
+        assert_eq!(
+            FileDiff::new(rebased_before, rebased_after).apply(&selection),
+            rebased_after
+        );
+    }
+
+    #[test]
+    fn relocate_requires_context() {
+        let hunks = get_hunks("a\nold\nb\n", "a\nnew\nb\n");
+        let stale_id = format!("{HUNK_ID_PREFIX}{}", "0".repeat(64));
+
+        let mut selection = HunkSelection::default();
+        selection.ids.insert(stale_id.clone());
+        selection.patterns.push(HunkPattern {
+            id: stale_id.clone(),
+            removed: "old\n".to_string(),
+            added: "new\n".to_string(),
+            context: None,
+        });
+
+        assert_eq!(
+            selection.relocate(&hunks),
+            vec![Relocation::Missing { id: stale_id }]
+        );
+        assert!(!selection.matches(0, &hunks[0].id));
+    }
+
+    #[test]
+    fn relocate_reports_ambiguous_and_unknown_ids() {
+        let before = "x\nold\nx\nold\nx\n";
+        let after = "x\nnew\nx\nnew\nx\n";
+        let hunks = get_hunks(before, after);
+        let stale_id = format!("{HUNK_ID_PREFIX}{}", "0".repeat(64));
+        let unknown_id = format!("{HUNK_ID_PREFIX}{}", "1".repeat(64));
+
+        let mut selection = HunkSelection::default();
+        selection.ids.insert(stale_id.clone());
+        selection.ids.insert(unknown_id.clone());
+        // The listed context sits next to both edits equally well.
+        selection.patterns.push(HunkPattern {
+            id: stale_id.clone(),
+            removed: "old\n".to_string(),
+            added: "new\n".to_string(),
+            context: Some(HunkContext {
+                before: "x\n".to_string(),
+                after: "x\n".to_string(),
+            }),
+        });
+
+        let relocations = selection.relocate(&hunks);
+        assert_eq!(
+            relocations,
+            vec![
+                Relocation::Missing { id: stale_id },
+                Relocation::Missing { id: unknown_id },
+            ]
+        );
+    }
+
+    #[test]
+    fn digest_hunk_ids_ignores_file_order() {
+        let first = get_hunks("a\n", "b\n");
+        let second = get_hunks("c\n", "d\n");
+
+        let forward = digest_hunk_ids([("a.txt", first.as_slice()), ("b.txt", second.as_slice())]);
+        let reverse = digest_hunk_ids([("b.txt", second.as_slice()), ("a.txt", first.as_slice())]);
+        let swapped = digest_hunk_ids([("a.txt", second.as_slice()), ("b.txt", first.as_slice())]);
+
+        assert_eq!(forward, reverse);
+        assert_ne!(forward, swapped);
+        assert!(forward.starts_with("sha256:"));
+    }
+
     #[test]
     fn normalize_hunk_id_accepts_prefixes() {
         let before = "foo\nbar\n";
@@ -454,4 +815,54 @@ mod tests {
         assert_eq!(normalize_hunk_id(&format!("sha256:{hex}")).as_deref(), Some(expected.as_str()));
         assert_eq!(normalize_hunk_id(hex).as_deref(), Some(expected.as_str()));
     }
+
+    #[test]
+    fn pairs_removed_and_added_lines_side_by_side() {
+        let hunks = get_hunks("a\nb\nc\nd\n", "a\nB\nB2\nd\n");
+        let lines = hunks[0].lines(None, None);
+        let rows: Vec<(Option<&str>, Option<&str>)> = side_by_side(&lines)
+            .into_iter()
+            .map(|(before, after)| (before.map(|l| l.text), after.map(|l| l.text)))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (Some("a"), Some("a")),
+                (Some("b"), Some("B")),
+                (Some("c"), Some("B2")),
+                (Some("d"), Some("d")),
+            ]
+        );
+    }
+
+    #[test]
+    fn clips_context_shared_with_the_next_hunk() {
+        let hunks = get_hunks("a\nb\nc\nd\ne\n", "a\nB\nc\nD\ne\n");
+        assert_eq!(hunks.len(), 2);
+        let lines = file_lines(&hunks);
+        let rows = |index: usize| {
+            lines[index]
+                .iter()
+                .map(|l| (l.kind, l.text, l.before, l.after))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            rows(0),
+            [
+                (LineKind::Context, "a", Some(1), Some(1)),
+                (LineKind::Removed, "b", Some(2), None),
+                (LineKind::Added, "B", None, Some(2)),
+                (LineKind::Context, "c", Some(3), Some(3)),
+            ]
+        );
+        assert_eq!(
+            rows(1),
+            [
+                (LineKind::Context, "c", Some(3), Some(3)),
+                (LineKind::Removed, "d", Some(4), None),
+                (LineKind::Added, "D", None, Some(4)),
+                (LineKind::Context, "e", Some(5), Some(5)),
+            ]
+        );
+    }
 }
@@ -0,0 +1,229 @@
+use crate::diff::{file_lines, side_by_side, Hunk, HunkLine, LineKind};
+use std::fmt::Write;
+
+/// One file of the review page, as listed by `jj-hunk list`.
+#[derive(Debug, Clone, Copy)]
+pub struct ReportFile<'a> {
+    pub path: &'a str,
+    pub status: &'a str,
+    pub renamed_from: Option<&'a str>,
+    pub hunks: &'a [Hunk],
+    pub binary: bool,
+}
+
+const STYLE: &str = r#"
+body { font: 14px/1.4 system-ui, sans-serif; margin: 0; color: #1f2328; }
+header { position: sticky; top: 0; background: #f6f8fa; border-bottom: 1px solid #d0d7de; padding: 8px 16px; display: flex; gap: 12px; align-items: center; z-index: 1; }
+header h1 { font-size: 16px; margin: 0; flex: 1; }
+main { padding: 0 16px 16px; }
+section.file { border: 1px solid #d0d7de; border-radius: 6px; margin-top: 16px; }
+section.file h2 { font-size: 14px; margin: 0; padding: 8px; background: #f6f8fa; border-bottom: 1px solid #d0d7de; }
+.status, .note { color: #59636e; font-weight: normal; }
+.note { margin: 8px; }
+.caption { padding: 4px 8px; font-family: ui-monospace, monospace; font-size: 12px; background: #ddf4ff; }
+table.diff { width: 100%; border-collapse: collapse; table-layout: fixed; font: 12px/1.5 ui-monospace, monospace; }
+table.diff td { padding: 0 8px; white-space: pre-wrap; word-break: break-all; vertical-align: top; }
+table.diff td.num { width: 4em; text-align: right; color: #59636e; user-select: none; }
+td.removed { background: #ffebe9; }
+td.added { background: #dafbe1; }
+td.empty { background: #f6f8fa; }
+textarea { width: 100%; height: 12em; font: 12px ui-monospace, monospace; box-sizing: border-box; }
+"#;
+
+const SCRIPT: &str = r#"
+const output = document.getElementById("spec");
+function buildSpec() {
+  const files = {};
+  for (const section of document.querySelectorAll("section.file")) {
+    const path = section.dataset.path;
+    if (section.dataset.binary === "true") {
+      if (section.querySelector(".file-toggle").checked) files[path] = { action: "keep" };
+      continue;
+    }
+    const ids = [...section.querySelectorAll(".hunk-toggle:checked")].map((box) => box.dataset.id);
+    if (ids.length) files[path] = { ids };
+  }
+  return { files, default: "reset" };
+}
+function update() {
+  output.value = JSON.stringify(buildSpec(), null, 2);
+}
+for (const toggle of document.querySelectorAll(".file-toggle")) {
+  toggle.addEventListener("change", () => {
+    for (const box of toggle.closest("section").querySelectorAll(".hunk-toggle")) box.checked = toggle.checked;
+    update();
+  });
+}
+for (const box of document.querySelectorAll(".hunk-toggle")) box.addEventListener("change", update);
+document.getElementById("copy-spec").addEventListener("click", async () => {
+  update();
+  try {
+    await navigator.clipboard.writeText(output.value);
+  } catch {
+    output.select();
+    document.execCommand("copy");
+  }
+  document.getElementById("copied").textContent = "Copied";
+});
+update();
+"#;
+
+/// Render a standalone review page with side-by-side diffs and a checkbox
+/// per hunk; "Copy spec" copies a spec selecting the checked hunks.
+pub fn render(title: &str, files: &[ReportFile]) -> String {
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{title}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n\
+         <header><h1>{title}</h1><span id=\"copied\"></span>\
+         <button id=\"copy-spec\" type=\"button\">Copy spec</button></header>\n<main>\n",
+        title = escape(title)
+    );
+    for file in files {
+        write_file(&mut out, file);
+    }
+    let _ = write!(
+        out,
+        "<h2>Spec</h2>\n<textarea id=\"spec\" readonly></textarea>\n</main>\n\
+         <script>{SCRIPT}</script>\n</body>\n</html>\n"
+    );
+    out
+}
+
+fn write_file(out: &mut String, file: &ReportFile) {
+    let _ = write!(
+        out,
+        "<section class=\"file\" data-path=\"{}\" data-binary=\"{}\">\n\
+         <h2><label><input type=\"checkbox\" class=\"file-toggle\"> {}</label> \
+         <span class=\"status\">{}",
+        escape(file.path),
+        file.binary,
+        escape(file.path),
+        escape(file.status)
+    );
+    if let Some(from) = file.renamed_from {
+        let _ = write!(out, " from {}", escape(from));
+    }
+    out.push_str("</span></h2>\n");
+    if file.binary {
+        out.push_str("<p class=\"note\">Binary file</p>\n");
+    }
+
+    for (hunk, lines) in file.hunks.iter().zip(file_lines(file.hunks)) {
+        let _ = writeln!(
+            out,
+            "<div class=\"caption\"><label><input type=\"checkbox\" class=\"hunk-toggle\" \
+             data-id=\"{id}\"> Hunk {} · {id} · {} · -{},{} +{},{}{}</label></div>",
+            hunk.index,
+            escape(&hunk.hunk_type),
+            hunk.before_range.start,
+            hunk.before_range.length,
+            hunk.after_range.start,
+            hunk.after_range.length,
+            if hunk.truncated == Some(true) {
+                " · truncated"
+            } else {
+                ""
+            },
+            id = escape(&hunk.id),
+        );
+        out.push_str("<table class=\"diff\">\n");
+        for (before, after) in side_by_side(&lines) {
+            out.push_str("<tr>");
+            write_cell(out, before, LineKind::Removed);
+            write_cell(out, after, LineKind::Added);
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n");
+    }
+    out.push_str("</section>\n");
+}
+
+fn write_cell(out: &mut String, line: Option<&HunkLine>, changed: LineKind) {
+    let Some(line) = line else {
+        out.push_str("<td class=\"num empty\"></td><td class=\"empty\"></td>");
+        return;
+    };
+    let number = if changed == LineKind::Removed {
+        line.before
+    } else {
+        line.after
+    };
+    let class = if line.kind == changed {
+        if changed == LineKind::Removed {
+            "removed"
+        } else {
+            "added"
+        }
+    } else {
+        "context"
+    };
+    let _ = write!(
+        out,
+        "<td class=\"num\">{}</td><td class=\"{class}\">{}</td>",
+        number.map(|n| n.to_string()).unwrap_or_default(),
+        escape(line.text)
+    );
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::get_hunks;
+
+    #[test]
+    fn page_escapes_content_and_carries_hunk_ids() {
+        let hunks = get_hunks("<a>\n", "<b & c>\n");
+        let page = render(
+            "review",
+            &[ReportFile {
+                path: "x.html",
+                status: "modified",
+                renamed_from: None,
+                hunks: &hunks,
+                binary: false,
+            }],
+        );
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(page.contains("data-path=\"x.html\""));
+        assert!(page.contains(&format!("data-id=\"{}\"", hunks[0].id)));
+        assert!(page.contains("<td class=\"removed\">&lt;a&gt;</td>"));
+        assert!(page.contains("<td class=\"added\">&lt;b &amp; c&gt;</td>"));
+        assert!(!page.contains("<b & c>"));
+    }
+
+    #[test]
+    fn context_stops_at_the_next_hunk() {
+        let hunks = get_hunks("a\nb\nc\nd\ne\n", "a\nB\nc\nD\ne\n");
+        let page = render(
+            "review",
+            &[ReportFile {
+                path: "notes.txt",
+                status: "modified",
+                renamed_from: None,
+                hunks: &hunks,
+                binary: false,
+            }],
+        );
+        assert_eq!(page.matches("<table class=\"diff\">").count(), 2);
+        assert_eq!(page.matches("<td class=\"removed\">d</td>").count(), 1);
+        assert!(!page.contains("<td class=\"context\">d</td>"));
+        assert!(page.contains("<td class=\"num\">3</td><td class=\"context\">c</td>"));
+    }
+}
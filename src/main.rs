@@ -1,11 +1,21 @@
 use anyhow::Result;
 use clap::{Args, Parser, Subcommand};
 
+mod backend;
+mod cache;
 mod diff;
 mod spec;
 mod commands;
+mod html;
+mod mcp;
+mod patch;
+mod server;
+mod template;
 
-use commands::{BinaryMode, ListFormat, ListGrouping, ListMode, ListOptions};
+use commands::{
+    BinaryMode, ColorChoice, HunkKind, ListFormat, ListGrouping, ListMode, ListOptions, ListSort,
+    PageUnit, Session,
+};
 
 #[derive(Parser)]
 #[command(name = "jj-hunk")]
@@ -18,7 +28,7 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// List hunks in current changes
-    List(ListArgs),
+    List(Box<ListArgs>),
 
     /// Select hunks (called by jj --tool)
     Select {
@@ -28,6 +38,17 @@ enum Commands {
         right: String,
     },
 
+    /// Copy both sides of a diff into a directory (called by jj diff --tool)
+    #[command(hide = true)]
+    Snapshot {
+        /// Path to "before" directory
+        left: String,
+        /// Path to "after" directory
+        right: String,
+        /// Directory receiving `left/` and `right/` copies
+        dest: String,
+    },
+
     /// Split changes with hunk selection
     Split {
         /// JSON/YAML spec string, or '-' for stdin (omit when using --spec-file)
@@ -37,6 +58,9 @@ enum Commands {
         /// Read spec from a file (JSON or YAML)
         #[arg(long = "spec-file", short = 'f')]
         spec_file: Option<String>,
+        /// Read the selection from a unified diff (e.g. edited `list --format patch` output), or '-' for stdin
+        #[arg(long, conflicts_with = "spec_file")]
+        patch: Option<String>,
         /// Revision to split (default: @)
         #[arg(short, long)]
         rev: Option<String>,
@@ -51,6 +75,9 @@ enum Commands {
         /// Read spec from a file (JSON or YAML)
         #[arg(long = "spec-file", short = 'f')]
         spec_file: Option<String>,
+        /// Read the selection from a unified diff (e.g. edited `list --format patch` output), or '-' for stdin
+        #[arg(long, conflicts_with = "spec_file")]
+        patch: Option<String>,
     },
 
     /// Squash selected hunks into parent
@@ -60,10 +87,50 @@ enum Commands {
         /// Read spec from a file (JSON or YAML)
         #[arg(long = "spec-file", short = 'f')]
         spec_file: Option<String>,
+        /// Read the selection from a unified diff (e.g. edited `list --format patch` output), or '-' for stdin
+        #[arg(long, conflicts_with = "spec_file")]
+        patch: Option<String>,
         /// Revision to squash (default: @)
         #[arg(short, long)]
         rev: Option<String>,
     },
+
+    /// Check a spec against the current diff without changing anything
+    Check {
+        /// JSON/YAML spec string, or '-' for stdin (omit when using --spec-file)
+        spec: Option<String>,
+        /// Read spec from a file (JSON or YAML)
+        #[arg(long = "spec-file", short = 'f')]
+        spec_file: Option<String>,
+        /// Revision the spec targets (default: @)
+        #[arg(short, long)]
+        rev: Option<String>,
+    },
+
+    /// Serve JSON-RPC requests over stdin/stdout
+    Serve,
+
+    /// Serve the Model Context Protocol over stdin/stdout
+    Mcp,
+
+    /// Undo the last split/commit/squash run by jj-hunk
+    Undo {
+        /// Restore even if other operations ran since
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Manage the on-disk hunk cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheAction {
+    /// Delete all cached hunks
+    Clear,
 }
 
 #[derive(Args)]
@@ -77,19 +144,52 @@ struct ListArgs {
     /// Exclude glob patterns (repeatable)
     #[arg(short = 'x', long)]
     exclude: Vec<String>,
+    /// jj fileset limiting the diff, e.g. 'glob:src/** & ~glob:**/*.snap' (repeatable)
+    #[arg(short = 'p', long)]
+    paths: Vec<String>,
     /// Group output by directory, extension, or status
     #[arg(long, value_enum, default_value_t = ListGrouping::None)]
     group: ListGrouping,
+    /// Sort files (default: diff order)
+    #[arg(long, value_enum)]
+    sort: Option<ListSort>,
+    /// Only list hunks of this type (repeatable)
+    #[arg(long = "type", value_enum)]
+    types: Vec<HunkKind>,
+    /// Only list hunks changing at least N lines (removed + added)
+    #[arg(long, visible_alias = "min-lines-changed")]
+    min_lines: Option<usize>,
+    /// Only list hunks changing at most N lines (removed + added)
+    #[arg(long)]
+    max_lines_changed: Option<usize>,
+    /// Only list hunks whose removed or added text matches this regex
+    #[arg(long)]
+    grep: Option<String>,
     /// Output format
     #[arg(long, value_enum, default_value_t = ListFormat::Json)]
     format: ListFormat,
+    /// Color text output (auto: only on a terminal, unless NO_COLOR is set)
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// Show text hunks as two columns, removed beside added
+    #[arg(long)]
+    side_by_side: bool,
+    /// Side-by-side width in characters (default: $COLUMNS, then 120)
+    #[arg(long)]
+    width: Option<usize>,
+    /// Render each file (or hunk) through a template, e.g. '{path}#{hunk.index} {hunk.id}\n'
+    #[arg(short = 'T', long)]
+    template: Option<String>,
+    /// Read the --template from a file
+    #[arg(long = "template-file", conflicts_with = "template")]
+    template_file: Option<String>,
     /// Binary handling
     #[arg(long, value_enum, default_value_t = BinaryMode::Mark)]
     binary: BinaryMode,
-    /// Truncate file contents to N bytes before diffing
+    /// Cut each hunk's removed/added text to N bytes (ids stay valid)
     #[arg(long)]
     max_bytes: Option<usize>,
-    /// Truncate file contents to N lines before diffing
+    /// Cut each hunk's removed/added text to N lines (ids stay valid)
     #[arg(long)]
     max_lines: Option<usize>,
     /// Optional JSON/YAML spec to preview (inline or '-')
@@ -101,18 +201,43 @@ struct ListArgs {
     /// Only list files with hunk counts
     #[arg(long, conflicts_with = "spec_template")]
     files: bool,
+    /// Only list added/removed line counts per hunk, file, group, and overall
+    #[arg(long, conflicts_with_all = ["files", "spec_template"])]
+    stat: bool,
     /// Output a spec template instead of hunks
     #[arg(long = "spec-template", conflicts_with = "files")]
     spec_template: bool,
+    /// Select hunks in the spec template by content and context (fuzzy: true)
+    #[arg(long, requires = "spec_template")]
+    fuzzy: bool,
+    /// List at most N files (or hunks, with --page-by hunk) per page
+    #[arg(long)]
+    limit: Option<usize>,
+    /// Skip the first N files (or hunks) of the listing
+    #[arg(long, default_value_t = 0)]
+    offset: usize,
+    /// Whether --limit, --offset and --cursor count files or hunks
+    #[arg(long, value_enum, default_value_t = PageUnit::File)]
+    page_by: PageUnit,
+    /// Resume where a previous page stopped (its next_cursor)
+    #[arg(long)]
+    cursor: Option<String>,
+    /// Stop adding files (or hunks) before the page's JSON exceeds N bytes
+    #[arg(long)]
+    max_output_bytes: Option<usize>,
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    let mut session = Session::default();
 
     match cli.command {
         Commands::List(args) => {
+            let args = *args;
             let mode = if args.files {
                 ListMode::Files
+            } else if args.stat {
+                ListMode::Stat
             } else if args.spec_template {
                 ListMode::SpecTemplate
             } else {
@@ -123,59 +248,153 @@ fn main() -> Result<()> {
                 rev: args.rev,
                 include: args.include,
                 exclude: args.exclude,
+                paths: args.paths,
                 group: args.group,
+                sort: args.sort,
+                types: args.types,
+                min_lines: args.min_lines,
+                max_lines_changed: args.max_lines_changed,
+                grep: args.grep,
                 format: args.format,
                 mode,
+                fuzzy: args.fuzzy,
                 spec: args.spec,
                 spec_file: args.spec_file,
                 binary: args.binary,
                 max_bytes: args.max_bytes,
                 max_lines: args.max_lines,
+                color: args.color,
+                side_by_side: args.side_by_side,
+                width: args.width,
+                template: args.template,
+                template_file: args.template_file,
+                limit: args.limit,
+                offset: args.offset,
+                page_by: args.page_by,
+                cursor: args.cursor,
+                max_output_bytes: args.max_output_bytes,
             };
 
-            commands::list(options)
+            commands::list(&mut session, options)
         }
         Commands::Select { left, right } => commands::select(&left, &right),
+        Commands::Snapshot { left, right, dest } => commands::snapshot(&left, &right, &dest),
         Commands::Split {
             spec,
             message,
             spec_file,
+            patch,
             rev,
         } => {
-            let (spec, message) = normalize_spec_message(spec, message, &spec_file, "split")?;
-            commands::split(spec.as_deref(), spec_file.as_deref(), &message, rev.as_deref())
+            let (spec, message) =
+                normalize_spec_message(spec, message, spec_source(&spec_file, &patch), "split")?;
+            let spec = match &patch {
+                Some(patch) => Some(commands::spec_from_patch(
+                    &mut session,
+                    patch,
+                    rev.as_deref(),
+                )?),
+                None => spec,
+            };
+            commands::split(
+                &mut session,
+                spec.as_deref(),
+                spec_file.as_deref(),
+                &message,
+                rev.as_deref(),
+            )
         }
         Commands::Commit {
             spec,
             message,
             spec_file,
+            patch,
+        } => {
+            let (spec, message) =
+                normalize_spec_message(spec, message, spec_source(&spec_file, &patch), "commit")?;
+            let spec = match &patch {
+                Some(patch) => Some(commands::spec_from_patch(&mut session, patch, None)?),
+                None => spec,
+            };
+            commands::commit(
+                &mut session,
+                spec.as_deref(),
+                spec_file.as_deref(),
+                &message,
+            )
+        }
+        Commands::Squash {
+            spec,
+            spec_file,
+            patch,
+            rev,
         } => {
-            let (spec, message) = normalize_spec_message(spec, message, &spec_file, "commit")?;
-            commands::commit(spec.as_deref(), spec_file.as_deref(), &message)
+            let spec = normalize_spec_only(spec, spec_source(&spec_file, &patch), "squash")?;
+            let spec = match &patch {
+                Some(patch) => Some(commands::spec_from_patch(
+                    &mut session,
+                    patch,
+                    rev.as_deref(),
+                )?),
+                None => spec,
+            };
+            commands::squash(
+                &mut session,
+                spec.as_deref(),
+                spec_file.as_deref(),
+                rev.as_deref(),
+            )
         }
-        Commands::Squash { spec, spec_file, rev } => {
-            let spec = normalize_spec_only(spec, &spec_file, "squash")?;
-            commands::squash(spec.as_deref(), spec_file.as_deref(), rev.as_deref())
+        Commands::Check {
+            spec,
+            spec_file,
+            rev,
+        } => {
+            let spec =
+                normalize_spec_only(spec, spec_file.as_ref().map(|_| "--spec-file"), "check")?;
+            commands::check(
+                &mut session,
+                spec.as_deref(),
+                spec_file.as_deref(),
+                rev.as_deref(),
+            )
         }
+        Commands::Serve => server::serve(),
+        Commands::Mcp => mcp::serve(),
+        Commands::Undo { force } => commands::undo(&mut session, force),
+        Commands::Cache {
+            action: CacheAction::Clear,
+        } => commands::cache_clear(&mut session),
+    }
+}
+
+/// The flag supplying the selection instead of a positional `<spec>`, if any.
+fn spec_source(spec_file: &Option<String>, patch: &Option<String>) -> Option<&'static str> {
+    if spec_file.is_some() {
+        Some("--spec-file")
+    } else if patch.is_some() {
+        Some("--patch")
+    } else {
+        None
     }
 }
 
 fn normalize_spec_message(
     mut spec: Option<String>,
     mut message: Option<String>,
-    spec_file: &Option<String>,
+    source: Option<&str>,
     command: &str,
 ) -> Result<(Option<String>, String)> {
-    if spec_file.is_some() && message.is_none() {
+    if source.is_some() && message.is_none() {
         message = spec.take();
     }
 
     let message = message
         .ok_or_else(|| anyhow::anyhow!("{command} requires a commit message"))?;
 
-    if spec_file.is_some() {
+    if let Some(source) = source {
         if spec.is_some() {
-            anyhow::bail!("{command}: omit <spec> when using --spec-file");
+            anyhow::bail!("{command}: omit <spec> when using {source}");
         }
         return Ok((None, message));
     }
@@ -187,12 +406,12 @@ fn normalize_spec_message(
 
 fn normalize_spec_only(
     spec: Option<String>,
-    spec_file: &Option<String>,
+    source: Option<&str>,
     command: &str,
 ) -> Result<Option<String>> {
-    if spec_file.is_some() {
+    if let Some(source) = source {
         if spec.is_some() {
-            anyhow::bail!("{command}: omit <spec> when using --spec-file");
+            anyhow::bail!("{command}: omit <spec> when using {source}");
         }
         return Ok(None);
     }
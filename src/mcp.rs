@@ -0,0 +1,340 @@
+use crate::commands::{self, deserialize_spec_input, ListOptions, Session, SpecInput};
+use crate::server::{self, parse_params, RpcError, INVALID_PARAMS, METHOD_NOT_FOUND};
+use anyhow::Result;
+use schemars::{schema_for, JsonSchema};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Protocol versions this server speaks, newest first.
+const PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
+/// List options naming local files. MCP clients pass specs and templates
+/// inline instead, so a tool call cannot read arbitrary files.
+const FILE_OPTIONS: &[&str] = &["spec_file", "template_file"];
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct PreviewArgs {
+    /// Spec to preview, as a JSON/YAML string or an object
+    #[serde(deserialize_with = "deserialize_spec_input")]
+    #[schemars(with = "SpecInput")]
+    spec: Option<String>,
+    /// Revision the spec targets (default: @)
+    #[serde(default)]
+    rev: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct SplitArgs {
+    /// Spec selecting the hunks for the first commit
+    #[serde(deserialize_with = "deserialize_spec_input")]
+    #[schemars(with = "SpecInput")]
+    spec: Option<String>,
+    /// Message of the first commit
+    message: String,
+    /// Revision to split (default: @)
+    #[serde(default)]
+    rev: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct CommitArgs {
+    /// Spec selecting the hunks to commit from the working copy
+    #[serde(deserialize_with = "deserialize_spec_input")]
+    #[schemars(with = "SpecInput")]
+    spec: Option<String>,
+    /// Commit message
+    message: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct SquashArgs {
+    /// Spec selecting the hunks to squash into the parent
+    #[serde(deserialize_with = "deserialize_spec_input")]
+    #[schemars(with = "SpecInput")]
+    spec: Option<String>,
+    /// Revision to squash (default: @)
+    #[serde(default)]
+    rev: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolCall {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+/// Serve the Model Context Protocol over stdio.
+pub fn serve() -> Result<()> {
+    let mut session = Session::persistent();
+    server::run_stdio(|method, params| dispatch(&mut session, method, params))
+}
+
+fn dispatch(session: &mut Session, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "initialize" => {
+            // Answer with the client's version if we speak it, else our latest.
+            let version = params
+                .get("protocolVersion")
+                .and_then(Value::as_str)
+                .and_then(|requested| {
+                    PROTOCOL_VERSIONS
+                        .iter()
+                        .find(|&&version| version == requested)
+                })
+                .unwrap_or(&PROTOCOL_VERSIONS[0]);
+            Ok(json!({
+                "protocolVersion": version,
+                "capabilities": {"tools": {}},
+                "serverInfo": {"name": "jj-hunk", "version": env!("CARGO_PKG_VERSION")},
+            }))
+        }
+        "ping" => Ok(json!({})),
+        "tools/list" => Ok(json!({ "tools": tools() })),
+        "tools/call" => {
+            let call: ToolCall = parse_params(params)?;
+            call_tool(session, &call.name, call.arguments)
+        }
+        _ if method.starts_with("notifications/") => Ok(Value::Null),
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method: {method}"),
+        )),
+    }
+}
+
+fn tools() -> Vec<Value> {
+    vec![
+        tool(
+            "list_hunks",
+            "List changed files and hunks of a revision (default: the working copy).",
+            list_schema(),
+        ),
+        tool(
+            "preview_spec",
+            "Show the hunks a spec would select and check it against the current diff.",
+            schema::<PreviewArgs>(),
+        ),
+        tool(
+            "split_by_spec",
+            "Split a revision: selected hunks go into the first commit, the rest stay.",
+            schema::<SplitArgs>(),
+        ),
+        tool(
+            "commit_by_spec",
+            "Commit the selected hunks of the working copy; the rest stay uncommitted.",
+            schema::<CommitArgs>(),
+        ),
+        tool(
+            "squash_by_spec",
+            "Squash the selected hunks of a revision into its parent.",
+            schema::<SquashArgs>(),
+        ),
+    ]
+}
+
+fn tool(name: &str, description: &str, input_schema: Value) -> Value {
+    json!({
+        "name": name,
+        "description": description,
+        "inputSchema": input_schema,
+    })
+}
+
+fn schema<T: JsonSchema>() -> Value {
+    serde_json::to_value(schema_for!(T)).expect("schema serialization should not fail")
+}
+
+/// `ListOptions` without the fields naming local files.
+fn list_schema() -> Value {
+    let mut schema = schema::<ListOptions>();
+    if let Some(properties) = schema["properties"].as_object_mut() {
+        for option in FILE_OPTIONS {
+            properties.remove(*option);
+        }
+    }
+    schema
+}
+
+fn call_tool(session: &mut Session, name: &str, arguments: Value) -> Result<Value, RpcError> {
+    let result = match name {
+        "list_hunks" => {
+            if let Some(option) = FILE_OPTIONS
+                .iter()
+                .find(|&&option| arguments.get(option).is_some())
+            {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    format!(
+                        "list_hunks does not read files; pass {option} contents inline instead"
+                    ),
+                ));
+            }
+            let options: ListOptions = parse_params(arguments)?;
+            server::reject_stdin_spec(options.spec.as_deref())?;
+            commands::list_value(session, &options)
+        }
+        "preview_spec" => {
+            let args: PreviewArgs = parse_params(arguments)?;
+            server::reject_stdin_spec(args.spec.as_deref())?;
+            preview(session, args)
+        }
+        "split_by_spec" => {
+            let args: SplitArgs = parse_params(arguments)?;
+            server::reject_stdin_spec(args.spec.as_deref())?;
+            commands::split(
+                session,
+                Some(required_spec(&args.spec)?),
+                None,
+                &args.message,
+                args.rev.as_deref(),
+            )
+            .map(|()| json!({"ok": true}))
+        }
+        "commit_by_spec" => {
+            let args: CommitArgs = parse_params(arguments)?;
+            server::reject_stdin_spec(args.spec.as_deref())?;
+            commands::commit(
+                session,
+                Some(required_spec(&args.spec)?),
+                None,
+                &args.message,
+            )
+            .map(|()| json!({"ok": true}))
+        }
+        "squash_by_spec" => {
+            let args: SquashArgs = parse_params(arguments)?;
+            server::reject_stdin_spec(args.spec.as_deref())?;
+            commands::squash(
+                session,
+                Some(required_spec(&args.spec)?),
+                None,
+                args.rev.as_deref(),
+            )
+            .map(|()| json!({"ok": true}))
+        }
+        _ => {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("Unknown tool: {name}"),
+            ))
+        }
+    };
+
+    // Tool failures are reported in the result so the model can see them.
+    Ok(match result {
+        Ok(value) => tool_result(value, false),
+        Err(err) => tool_result(Value::String(format!("{err:#}")), true),
+    })
+}
+
+fn preview(session: &mut Session, args: PreviewArgs) -> anyhow::Result<Value> {
+    let spec = args.spec.clone();
+    let report = commands::check_report(session, spec.as_deref(), None, args.rev.as_deref())?;
+    let options = ListOptions {
+        rev: args.rev,
+        spec,
+        ..ListOptions::default()
+    };
+    let selected = commands::list_value(session, &options)?;
+    Ok(json!({
+        "check": report,
+        "selected": selected,
+    }))
+}
+
+fn required_spec(spec: &Option<String>) -> Result<&str, RpcError> {
+    spec.as_deref()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "spec is required"))
+}
+
+fn tool_result(value: Value, is_error: bool) -> Value {
+    let text = match &value {
+        Value::String(text) => text.clone(),
+        other => serde_json::to_string_pretty(other).expect("JSON serialization should not fail"),
+    };
+    let mut result = json!({
+        "content": [{"type": "text", "text": text}],
+        "isError": is_error,
+    });
+    if value.is_object() {
+        result["structuredContent"] = value;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tools_list_exposes_generated_schemas() {
+        let mut session = Session::persistent();
+        let listed = dispatch(&mut session, "tools/list", Value::Null).unwrap();
+        let tools = listed["tools"].as_array().unwrap();
+
+        let names: Vec<&str> = tools.iter().map(|t| t["name"].as_str().unwrap()).collect();
+        assert_eq!(
+            names,
+            [
+                "list_hunks",
+                "preview_spec",
+                "split_by_spec",
+                "commit_by_spec",
+                "squash_by_spec"
+            ]
+        );
+
+        let list_schema = &tools[0]["inputSchema"];
+        assert!(list_schema["properties"]["max_lines"].is_object());
+        assert!(list_schema["properties"]["template"].is_object());
+        assert!(list_schema["properties"].get("spec_file").is_none());
+        assert!(list_schema["properties"].get("template_file").is_none());
+        assert_eq!(list_schema["additionalProperties"], false);
+
+        let commit_schema = tools[3]["inputSchema"].to_string();
+        assert!(commit_schema.contains("\"default\""), "{commit_schema}");
+        assert!(commit_schema.contains("\"expect\""), "{commit_schema}");
+        let required = tools[3]["inputSchema"]["required"].as_array().unwrap();
+        assert!(required.contains(&json!("spec")));
+        assert!(required.contains(&json!("message")));
+    }
+
+    #[test]
+    fn tool_argument_errors_are_invalid_params() {
+        let mut session = Session::persistent();
+
+        let unknown = json!({"name": "rebase_by_spec", "arguments": {}});
+        let err = dispatch(&mut session, "tools/call", unknown).unwrap_err();
+        assert_eq!(err.code, INVALID_PARAMS);
+
+        let missing_message =
+            json!({"name": "commit_by_spec", "arguments": {"spec": {"files": {}}}});
+        let err = dispatch(&mut session, "tools/call", missing_message).unwrap_err();
+        assert_eq!(err.code, INVALID_PARAMS);
+
+        let spec_file = json!({"name": "list_hunks", "arguments": {"spec_file": "/etc/passwd"}});
+        let err = dispatch(&mut session, "tools/call", spec_file).unwrap_err();
+        assert_eq!(err.code, INVALID_PARAMS);
+    }
+
+    #[test]
+    fn initialize_negotiates_protocol_version() {
+        let mut session = Session::persistent();
+        let params = json!({"protocolVersion": "2025-03-26", "capabilities": {}});
+        let result = dispatch(&mut session, "initialize", params).unwrap();
+
+        assert_eq!(result["protocolVersion"], "2025-03-26");
+        assert!(result["capabilities"]["tools"].is_object());
+        assert_eq!(result["serverInfo"]["name"], "jj-hunk");
+
+        for params in [json!({"protocolVersion": "2099-01-01"}), json!({})] {
+            let result = dispatch(&mut session, "initialize", params).unwrap();
+            assert_eq!(result["protocolVersion"], PROTOCOL_VERSIONS[0]);
+        }
+    }
+}
@@ -0,0 +1,500 @@
+use crate::diff::{normalize_hunk_id, Hunk};
+use anyhow::Result;
+use std::fmt::Write;
+
+/// One file of a unified diff, as listed by `jj-hunk list`.
+#[derive(Debug, Clone, Copy)]
+pub struct PatchFile<'a> {
+    pub status: &'a str,
+    pub old_path: &'a str,
+    pub new_path: &'a str,
+    pub hunks: &'a [Hunk],
+    pub binary: bool,
+}
+
+/// One file of a parsed unified diff. Paths are `None` for `/dev/null`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParsedFile {
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub binary: bool,
+    pub hunks: Vec<ParsedHunk>,
+}
+
+/// One run of changed lines from a parsed `@@` block.
+///
+/// Blocks whose changes are separated by context lines (as in plain `git
+/// diff` output) yield one `ParsedHunk` per run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParsedHunk {
+    /// The id from the `@@` trailer, if the block held a single run.
+    pub id: Option<String>,
+    /// First before-side line of the run, counting from 1.
+    pub before_start: usize,
+    pub removed: String,
+    pub added: String,
+}
+
+impl ParsedFile {
+    /// The path the file has after the change, or before it if removed.
+    pub fn path(&self) -> Option<&str> {
+        self.new_path.as_deref().or(self.old_path.as_deref())
+    }
+}
+
+/// Render `files` as a git-style unified diff.
+///
+/// Every hunk gets its own `@@` header with the hunk id as the trailer, so
+/// context is clipped where it would run into a neighbouring hunk.
+pub fn render(files: &[PatchFile]) -> String {
+    let mut out = String::new();
+    for file in files {
+        write_file(&mut out, file);
+    }
+    out
+}
+
+fn write_file(out: &mut String, file: &PatchFile) {
+    let old_path = (file.status != "added").then(|| format!("a/{}", file.old_path));
+    let new_path = (file.status != "removed").then(|| format!("b/{}", file.new_path));
+    let old_path = old_path.as_deref().unwrap_or("/dev/null");
+    let new_path = new_path.as_deref().unwrap_or("/dev/null");
+
+    // No mode lines: listed files do not carry their modes, and `/dev/null`
+    // already marks added and removed files.
+    let _ = writeln!(out, "diff --git a/{} b/{}", file.old_path, file.new_path);
+    match file.status {
+        "renamed" | "copied" if file.old_path != file.new_path => {
+            let verb = if file.status == "renamed" {
+                "rename"
+            } else {
+                "copy"
+            };
+            let _ = writeln!(out, "{verb} from {}", file.old_path);
+            let _ = writeln!(out, "{verb} to {}", file.new_path);
+        }
+        _ => {}
+    }
+
+    if file.binary {
+        let _ = writeln!(out, "Binary files {old_path} and {new_path} differ");
+        return;
+    }
+    if file.hunks.is_empty() {
+        return;
+    }
+
+    let _ = writeln!(out, "--- {old_path}");
+    let _ = writeln!(out, "+++ {new_path}");
+
+    // Lines added minus lines removed by the hunks written so far.
+    let mut offset = 0isize;
+    // First before-side line not yet written, so context never repeats.
+    let mut previous_end = 0;
+    for (position, hunk) in file.hunks.iter().enumerate() {
+        let start = hunk.before_range.start.saturating_sub(1);
+        let end = start + hunk.before_range.length;
+        let next_start = file
+            .hunks
+            .get(position + 1)
+            .map_or(usize::MAX, |next| next.before_range.start.saturating_sub(1));
+
+        let (pre, post) = match &hunk.context {
+            Some(context) => (
+                last_lines(&context.before, start.saturating_sub(previous_end)),
+                first_lines(&context.after, next_start.saturating_sub(end)),
+            ),
+            None => (Vec::new(), Vec::new()),
+        };
+
+        let old_start = start - pre.len();
+        let old_length = pre.len() + hunk.before_range.length + post.len();
+        let new_start = old_start.saturating_add_signed(offset);
+        let new_length = pre.len() + hunk.after_range.length + post.len();
+        let _ = writeln!(
+            out,
+            "@@ -{} +{} @@ {}",
+            format_range(old_start, old_length),
+            format_range(new_start, new_length),
+            hunk.id
+        );
+
+        for line in &pre {
+            write_line(out, ' ', line);
+        }
+        for line in hunk.removed.split_inclusive('\n') {
+            write_line(out, '-', line);
+        }
+        for line in hunk.added.split_inclusive('\n') {
+            write_line(out, '+', line);
+        }
+        for line in &post {
+            write_line(out, ' ', line);
+        }
+
+        offset += hunk.after_range.length as isize - hunk.before_range.length as isize;
+        previous_end = end + post.len();
+    }
+}
+
+/// A `start,length` range; empty ranges point at the line before them.
+fn format_range(start: usize, length: usize) -> String {
+    match length {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{length}", start + 1),
+    }
+}
+
+fn write_line(out: &mut String, prefix: char, line: &str) {
+    out.push(prefix);
+    out.push_str(line);
+    if !line.ends_with('\n') {
+        out.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+fn first_lines(text: &str, limit: usize) -> Vec<&str> {
+    text.split_inclusive('\n').take(limit).collect()
+}
+
+fn last_lines(text: &str, limit: usize) -> Vec<&str> {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    lines[lines.len().saturating_sub(limit)..].to_vec()
+}
+
+/// Parse a unified diff, such as `list --format patch` output or `git diff`.
+pub fn parse(text: &str) -> Result<Vec<ParsedFile>> {
+    let mut files: Vec<ParsedFile> = Vec::new();
+    let mut lines = text.split_inclusive('\n').enumerate().peekable();
+
+    while let Some((number, line)) = lines.next() {
+        let content = line.trim_end_matches(['\n', '\r']);
+        if let Some(paths) = content.strip_prefix("diff --git ") {
+            let (old_path, new_path) = git_header_paths(paths)
+                .ok_or_else(|| anyhow::anyhow!("line {}: malformed diff header", number + 1))?;
+            files.push(ParsedFile {
+                old_path: Some(old_path.to_string()),
+                new_path: Some(new_path.to_string()),
+                ..ParsedFile::default()
+            });
+            continue;
+        }
+        if let Some(path) = content.strip_prefix("--- ") {
+            // A plain unified diff starts its file at `---`.
+            if files.last().is_none_or(|file| !file.hunks.is_empty()) {
+                files.push(ParsedFile::default());
+            }
+            let file = files.last_mut().expect("pushed above");
+            file.old_path = header_path(path, "a/");
+            continue;
+        }
+
+        let Some(file) = files.last_mut() else {
+            continue;
+        };
+        if let Some(path) = content.strip_prefix("+++ ") {
+            file.new_path = header_path(path, "b/");
+        } else if let Some(path) = content
+            .strip_prefix("rename from ")
+            .or_else(|| content.strip_prefix("copy from "))
+        {
+            file.old_path = Some(path.to_string());
+        } else if let Some(path) = content
+            .strip_prefix("rename to ")
+            .or_else(|| content.strip_prefix("copy to "))
+        {
+            file.new_path = Some(path.to_string());
+        } else if content.starts_with("new file mode") {
+            file.old_path = None;
+        } else if content.starts_with("deleted file mode") {
+            file.new_path = None;
+        } else if let Some(paths) = content.strip_prefix("Binary files ") {
+            file.binary = true;
+            if paths.starts_with("/dev/null and ") {
+                file.old_path = None;
+            } else if paths.ends_with(" and /dev/null differ") {
+                file.new_path = None;
+            }
+        } else if content.starts_with("@@ ") {
+            let header = parse_hunk_header(content)
+                .ok_or_else(|| anyhow::anyhow!("line {}: malformed @@ header", number + 1))?;
+            let hunks = parse_hunk_body(header, &mut lines)
+                .map_err(|err| anyhow::anyhow!("line {}: {err}", number + 1))?;
+            file.hunks.extend(hunks);
+        }
+    }
+
+    Ok(files)
+}
+
+/// The `@@ -a,b +c,d @@ trailer` line of a block.
+struct HunkHeader {
+    before_start: usize,
+    before_length: usize,
+    after_length: usize,
+    id: Option<String>,
+}
+
+fn parse_hunk_header(line: &str) -> Option<HunkHeader> {
+    let (ranges, trailer) = line.strip_prefix("@@ ")?.split_once(" @@")?;
+    let (before, after) = ranges.split_once(' ')?;
+    let (before_start, before_length) = parse_range(before.strip_prefix('-')?)?;
+    let (_, after_length) = parse_range(after.strip_prefix('+')?)?;
+    let id = trailer
+        .split_whitespace()
+        .next()
+        .and_then(normalize_hunk_id);
+    Some(HunkHeader {
+        // Empty ranges point at the line before them.
+        before_start: if before_length == 0 {
+            before_start + 1
+        } else {
+            before_start
+        },
+        before_length,
+        after_length,
+        id,
+    })
+}
+
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    match range.split_once(',') {
+        Some((start, length)) => Some((start.parse().ok()?, length.parse().ok()?)),
+        None => Some((range.parse().ok()?, 1)),
+    }
+}
+
+fn parse_hunk_body<'a, I>(
+    header: HunkHeader,
+    lines: &mut std::iter::Peekable<I>,
+) -> Result<Vec<ParsedHunk>, String>
+where
+    I: Iterator<Item = (usize, &'a str)>,
+{
+    let mut hunks: Vec<ParsedHunk> = Vec::new();
+    let (mut before_left, mut after_left) = (header.before_length, header.after_length);
+    let mut before_line = header.before_start;
+    // Which side the previous line went to, for `\ No newline` markers.
+    let mut last: Option<char> = None;
+    let mut in_run = false;
+
+    while before_left > 0
+        || after_left > 0
+        || lines.peek().is_some_and(|(_, l)| l.starts_with('\\'))
+    {
+        let Some((_, line)) = lines.next() else {
+            return Err("patch ends in the middle of a hunk".to_string());
+        };
+        let (prefix, text) = match line.chars().next() {
+            // Editors often strip the space of blank context lines.
+            Some('\n' | '\r') => (' ', line),
+            Some(prefix) => (prefix, &line[1..]),
+            None => (' ', line),
+        };
+        match prefix {
+            ' ' if before_left > 0 && after_left > 0 => {
+                before_left -= 1;
+                after_left -= 1;
+                before_line += 1;
+                in_run = false;
+            }
+            '-' | '+' => {
+                if !in_run {
+                    hunks.push(ParsedHunk {
+                        before_start: before_line,
+                        ..ParsedHunk::default()
+                    });
+                    in_run = true;
+                }
+                let hunk = hunks.last_mut().expect("pushed above");
+                if prefix == '-' && before_left > 0 {
+                    hunk.removed.push_str(text);
+                    before_left -= 1;
+                    before_line += 1;
+                } else if prefix == '+' && after_left > 0 {
+                    hunk.added.push_str(text);
+                    after_left -= 1;
+                } else {
+                    return Err("hunk has more lines than its @@ header says".to_string());
+                }
+            }
+            '\\' => {
+                if let (Some(side), Some(hunk)) = (last, hunks.last_mut()) {
+                    let text = if side == '-' {
+                        &mut hunk.removed
+                    } else {
+                        &mut hunk.added
+                    };
+                    if in_run && side != ' ' && text.ends_with('\n') {
+                        text.pop();
+                    }
+                }
+                continue;
+            }
+            _ => return Err(format!("unexpected line in hunk: {}", line.trim_end())),
+        }
+        last = Some(prefix);
+    }
+
+    if hunks.len() == 1 {
+        hunks[0].id = header.id;
+    }
+    Ok(hunks)
+}
+
+/// The path in a `---`/`+++` line, or `None` for `/dev/null`.
+/// Split the `a/<old> b/<new>` of a `diff --git` line.
+///
+/// Paths may contain ` b/` themselves, so prefer the split where both halves
+/// agree, as they do unless the file was renamed or copied; `rename`/`copy`
+/// and `---`/`+++` lines correct the paths of those.
+fn git_header_paths(paths: &str) -> Option<(&str, &str)> {
+    let paths = paths.strip_prefix("a/")?;
+    let splits = paths
+        .match_indices(" b/")
+        .map(|(at, _)| (&paths[..at], &paths[at + 3..]));
+    splits
+        .clone()
+        .find(|(old, new)| old == new)
+        .or_else(|| splits.last())
+}
+
+fn header_path(path: &str, prefix: &str) -> Option<String> {
+    let path = path.split('\t').next().unwrap_or(path).trim_end();
+    if path == "/dev/null" {
+        return None;
+    }
+    Some(path.strip_prefix(prefix).unwrap_or(path).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::get_hunks;
+
+    fn modified<'a>(path: &'a str, hunks: &'a [Hunk]) -> PatchFile<'a> {
+        PatchFile {
+            status: "modified",
+            old_path: path,
+            new_path: path,
+            hunks,
+            binary: false,
+        }
+    }
+
+    #[test]
+    fn renders_git_style_hunks_with_ids() {
+        let hunks = get_hunks("a\nb\nc\nd\ne\n", "a\nb\nC\nd\ne\n");
+        let patch = render(&[modified("f.txt", &hunks)]);
+        assert_eq!(
+            patch,
+            format!(
+                "diff --git a/f.txt b/f.txt\n--- a/f.txt\n+++ b/f.txt\n\
+                 @@ -1,5 +1,5 @@ {}\n a\n b\n-c\n+C\n d\n e\n",
+                hunks[0].id
+            )
+        );
+    }
+
+    #[test]
+    fn clips_context_between_neighbouring_hunks() {
+        let before = "1\n2\n3\n4\n5\n6\n7\n8\n";
+        let after = "1\nTWO\n3\n4\n5\nSIX\n7\n8\n";
+        let hunks = get_hunks(before, after);
+        let patch = render(&[modified("n.txt", &hunks)]);
+        let headers: Vec<&str> = patch.lines().filter(|l| l.starts_with("@@")).collect();
+        assert_eq!(headers[0], format!("@@ -1,5 +1,5 @@ {}", hunks[0].id));
+        assert_eq!(headers[1], format!("@@ -6,3 +6,3 @@ {}", hunks[1].id));
+
+        // Only the second hunk: new-side numbers ignore the skipped one.
+        let patch = render(&[modified("n.txt", &hunks[1..])]);
+        assert!(patch.contains(&format!("@@ -3,6 +3,6 @@ {}", hunks[1].id)));
+    }
+
+    #[test]
+    fn parses_rendered_patches_back_into_hunks() {
+        let before = "1\n2\n3\n4\n5\n6\n7\n8\n";
+        let hunks = get_hunks(before, "1\nTWO\n3\n4\n5\nSIX\n7\n8");
+        let parsed = parse(&render(&[modified("n.txt", &hunks)])).unwrap();
+
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].path(), Some("n.txt"));
+        let ids: Vec<_> = parsed[0].hunks.iter().map(|h| h.id.clone()).collect();
+        assert_eq!(
+            ids,
+            hunks.iter().map(|h| Some(h.id.clone())).collect::<Vec<_>>()
+        );
+        for (parsed, hunk) in parsed[0].hunks.iter().zip(&hunks) {
+            assert_eq!(parsed.removed, hunk.removed);
+            assert_eq!(parsed.added, hunk.added);
+            assert_eq!(parsed.before_start, hunk.before_range.start);
+        }
+    }
+
+    #[test]
+    fn splits_plain_diff_blocks_into_runs() {
+        let patch = "--- a/n.txt\n+++ b/n.txt\n@@ -1,6 +1,6 @@ fn main()\n 1\n-2\n+TWO\n 3\n\n 5\n-6\n+SIX\n";
+        let parsed = parse(patch).unwrap();
+        let hunks = &parsed[0].hunks;
+        assert_eq!(hunks.len(), 2);
+        assert_eq!((hunks[0].before_start, hunks[0].id.as_deref()), (2, None));
+        assert_eq!(
+            (hunks[1].removed.as_str(), hunks[1].added.as_str()),
+            ("6\n", "SIX\n")
+        );
+        assert_eq!(hunks[1].before_start, 6);
+
+        assert!(parse("--- a/n.txt\n+++ b/n.txt\n@@ -1,2 +1,2 @@\n-1\n").is_err());
+    }
+
+    #[test]
+    fn parses_git_headers_of_paths_containing_b_slash() {
+        let patch = "diff --git a/a b/c.txt b/a b/c.txt\n\
+                     Binary files a/a b/c.txt and b/a b/c.txt differ\n\
+                     diff --git a/x b/y b/x b/z\nrename from x b/y\nrename to x b/z\n";
+        let parsed = parse(patch).unwrap();
+        assert_eq!(parsed[0].old_path.as_deref(), Some("a b/c.txt"));
+        assert_eq!(parsed[0].new_path.as_deref(), Some("a b/c.txt"));
+        assert_eq!(parsed[1].old_path.as_deref(), Some("x b/y"));
+        assert_eq!(parsed[1].new_path.as_deref(), Some("x b/z"));
+    }
+
+    #[test]
+    fn marks_new_files_and_missing_newlines() {
+        let hunks = get_hunks("", "hello");
+        let patch = render(&[PatchFile {
+            status: "added",
+            old_path: "new.txt",
+            new_path: "new.txt",
+            hunks: &hunks,
+            binary: false,
+        }]);
+        assert_eq!(
+            patch,
+            format!(
+                "diff --git a/new.txt b/new.txt\n--- /dev/null\n\
+                 +++ b/new.txt\n@@ -0,0 +1 @@ {}\n+hello\n\\ No newline at end of file\n",
+                hunks[0].id
+            )
+        );
+        let parsed = parse(&patch).unwrap();
+        assert_eq!(parsed[0].old_path, None);
+        assert_eq!(parsed[0].new_path.as_deref(), Some("new.txt"));
+
+        let removed = render(&[PatchFile {
+            status: "removed",
+            old_path: "logo.png",
+            new_path: "logo.png",
+            hunks: &[],
+            binary: true,
+        }]);
+        assert_eq!(
+            removed,
+            "diff --git a/logo.png b/logo.png\nBinary files a/logo.png and /dev/null differ\n"
+        );
+        let parsed = parse(&removed).unwrap();
+        assert_eq!(parsed[0].old_path.as_deref(), Some("logo.png"));
+        assert_eq!(parsed[0].new_path, None);
+    }
+}
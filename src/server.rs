@@ -0,0 +1,269 @@
+use crate::commands::{self, deserialize_spec_input, ListOptions, Session};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, Write};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const COMMAND_FAILED: i64 = -32000;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpecParams {
+    #[serde(default, deserialize_with = "deserialize_spec_input")]
+    spec: Option<String>,
+    #[serde(default)]
+    spec_file: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    rev: Option<String>,
+}
+
+/// Serve line-delimited JSON-RPC 2.0 requests on stdin until EOF.
+///
+/// One session is shared by all requests, so file contents, hunks and the
+/// jj tool configuration are only computed once per revision.
+pub fn serve() -> Result<()> {
+    let mut session = Session::persistent();
+    run_stdio(|method, params| dispatch(&mut session, method, params))
+}
+
+/// Answer JSON-RPC requests read line by line from stdin until EOF.
+pub fn run_stdio<F>(mut dispatch: F) -> Result<()>
+where
+    F: FnMut(&str, Value) -> Result<Value, RpcError>,
+{
+    let stdin = io::stdin();
+    let mut stdout = io::stdout().lock();
+
+    for line in stdin.lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = handle_line(&mut dispatch, &line) {
+            writeln!(stdout, "{}", serde_json::to_string(&response)?)?;
+            stdout.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn handle_line<F>(dispatch: &mut F, line: &str) -> Option<Response>
+where
+    F: FnMut(&str, Value) -> Result<Value, RpcError>,
+{
+    let value: Value = match serde_json::from_str(line) {
+        Ok(value) => value,
+        Err(err) => return Some(error_response(Value::Null, PARSE_ERROR, err.to_string())),
+    };
+    let request: Request = match serde_json::from_value(value) {
+        Ok(request) => request,
+        Err(err) => {
+            return Some(error_response(
+                Value::Null,
+                INVALID_REQUEST,
+                err.to_string(),
+            ))
+        }
+    };
+
+    let result = dispatch(&request.method, request.params);
+    // Requests without an id are notifications and never get a response.
+    let id = request.id?;
+    Some(match result {
+        Ok(result) => Response {
+            jsonrpc: "2.0",
+            id,
+            result: Some(result),
+            error: None,
+        },
+        Err(error) => Response {
+            jsonrpc: "2.0",
+            id,
+            result: None,
+            error: Some(error),
+        },
+    })
+}
+
+fn dispatch(session: &mut Session, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "list" => {
+            let options: ListOptions = parse_params(params)?;
+            reject_stdin_spec(options.spec.as_deref())?;
+            commands::list_value(session, &options).map_err(command_error)
+        }
+        "check" => {
+            let params: SpecParams = parse_params(params)?;
+            reject_stdin_spec(params.spec.as_deref())?;
+            let report = commands::check_report(
+                session,
+                params.spec.as_deref(),
+                params.spec_file.as_deref(),
+                params.rev.as_deref(),
+            )
+            .map_err(command_error)?;
+            serde_json::to_value(report).map_err(|err| command_error(err.into()))
+        }
+        "split" | "commit" | "squash" => {
+            let params: SpecParams = parse_params(params)?;
+            reject_stdin_spec(params.spec.as_deref())?;
+            let spec = params.spec.as_deref();
+            let spec_file = params.spec_file.as_deref();
+            let rev = params.rev.as_deref();
+            let result = match method {
+                "split" => commands::split(session, spec, spec_file, &message(&params)?, rev),
+                "commit" => {
+                    if rev.is_some() {
+                        return Err(RpcError::new(INVALID_PARAMS, "commit does not accept rev"));
+                    }
+                    commands::commit(session, spec, spec_file, &message(&params)?)
+                }
+                _ => commands::squash(session, spec, spec_file, rev),
+            };
+            result.map(|()| json!({"ok": true})).map_err(command_error)
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method: {method}"),
+        )),
+    }
+}
+
+pub fn parse_params<T>(params: Value) -> Result<T, RpcError>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+}
+
+fn message(params: &SpecParams) -> Result<String, RpcError> {
+    params
+        .message
+        .clone()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "message is required"))
+}
+
+pub fn reject_stdin_spec(spec: Option<&str>) -> Result<(), RpcError> {
+    if spec == Some("-") {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            "spec '-' (stdin) is not available in serve mode",
+        ));
+    }
+    Ok(())
+}
+
+pub fn command_error(err: anyhow::Error) -> RpcError {
+    RpcError::new(COMMAND_FAILED, format!("{err:#}"))
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Response {
+    Response {
+        jsonrpc: "2.0",
+        id,
+        result: None,
+        error: Some(RpcError::new(code, message)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(session: &mut Session, line: &str) -> Option<Response> {
+        handle_line(
+            &mut |method: &str, params| dispatch(session, method, params),
+            line,
+        )
+    }
+
+    fn error_code(response: Option<Response>) -> i64 {
+        response
+            .expect("expected a response")
+            .error
+            .expect("expected an error")
+            .code
+    }
+
+    #[test]
+    fn malformed_requests_get_protocol_errors() {
+        let mut session = Session::persistent();
+
+        assert_eq!(error_code(call(&mut session, "{not json")), PARSE_ERROR);
+        assert_eq!(
+            error_code(call(&mut session, r#"{"id": 1}"#)),
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            error_code(call(&mut session, r#"{"id": 1, "method": "rebase"}"#)),
+            METHOD_NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn invalid_params_are_rejected_before_running_jj() {
+        let mut session = Session::persistent();
+
+        let unknown_field = r#"{"id": 1, "method": "list", "params": {"colour": "red"}}"#;
+        assert_eq!(
+            error_code(call(&mut session, unknown_field)),
+            INVALID_PARAMS
+        );
+
+        let stdin_spec =
+            r#"{"id": 2, "method": "commit", "params": {"spec": "-", "message": "m"}}"#;
+        assert_eq!(error_code(call(&mut session, stdin_spec)), INVALID_PARAMS);
+
+        let no_message = r#"{"id": 3, "method": "split", "params": {"spec": {"files": {}}}}"#;
+        assert_eq!(error_code(call(&mut session, no_message)), INVALID_PARAMS);
+    }
+
+    #[test]
+    fn notifications_get_no_response() {
+        let mut session = Session::persistent();
+        let notification = r#"{"method": "rebase"}"#;
+        assert!(call(&mut session, notification).is_none());
+    }
+}
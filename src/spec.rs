@@ -1,28 +1,60 @@
-use crate::diff::{normalize_hunk_id, HunkSelection};
+use crate::diff::{normalize_hunk_id, HunkContext, HunkPattern, HunkSelection};
+use schemars::JsonSchema;
 use serde::de::{self, Deserializer};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct Spec {
+    /// Per-file selections, keyed by repo-relative path.
     #[serde(default)]
     pub files: HashMap<String, FileSpec>,
+    /// Action for files not listed in `files`.
     #[serde(default)]
     pub default: DefaultAction,
+    /// Refuse to run unless the target revision still matches.
+    #[serde(default)]
+    pub expect: Option<Expectation>,
+    /// Relocate stale hunk ids by content instead of ignoring them.
+    #[serde(default)]
+    pub fuzzy: bool,
 }
 
-#[derive(Debug, Deserialize)]
+/// State of the target revision the spec was written against.
+///
+/// Every field is optional; only the ones present are checked before a
+/// mutating command runs.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Expectation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operation_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hunks: Option<String>,
+    /// Paths `hunks` was computed over; defaults to the paths in `files`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paths: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum FileSpec {
     Selection(HunkSpec),
     Action { action: Action },
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Deserialize, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct HunkSpec {
+    /// Hunks to keep: indices, ids, or hunk objects from `list`.
     #[serde(default, deserialize_with = "deserialize_hunk_selectors")]
+    #[schemars(with = "Vec<HunkSelectorInput>")]
     pub hunks: Vec<HunkSelector>,
+    /// Ids of hunks to keep.
     #[serde(default, deserialize_with = "deserialize_hunk_ids")]
     pub ids: Vec<String>,
 }
@@ -38,6 +70,10 @@ impl HunkSpec {
                 HunkSelector::Id(id) => {
                     selection.ids.insert(id.clone());
                 }
+                HunkSelector::Hunk(pattern) => {
+                    selection.ids.insert(pattern.id.clone());
+                    selection.patterns.push(pattern.clone());
+                }
             }
         }
         for id in &self.ids {
@@ -51,13 +87,27 @@ impl HunkSpec {
 pub enum HunkSelector {
     Index(usize),
     Id(String),
+    /// A hunk object as emitted by `list`, selected by id.
+    Hunk(HunkPattern),
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(untagged)]
 enum HunkSelectorInput {
     Index(usize),
     Id(String),
+    Hunk(HunkInput),
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct HunkInput {
+    id: String,
+    #[serde(default)]
+    removed: String,
+    #[serde(default)]
+    added: String,
+    #[serde(default)]
+    context: Option<HunkContext>,
 }
 
 fn deserialize_hunk_selectors<'de, D>(deserializer: D) -> Result<Vec<HunkSelector>, D::Error>
@@ -84,6 +134,17 @@ where
                     parsed.push(HunkSelector::Id(id));
                 }
             }
+            HunkSelectorInput::Hunk(hunk) => {
+                let id = normalize_hunk_id(&hunk.id).ok_or_else(|| {
+                    de::Error::custom(format!("Invalid hunk selector id: {}", hunk.id))
+                })?;
+                parsed.push(HunkSelector::Hunk(HunkPattern {
+                    id,
+                    removed: hunk.removed,
+                    added: hunk.added,
+                    context: hunk.context,
+                }));
+            }
         }
     }
 
@@ -108,14 +169,14 @@ where
     Ok(parsed)
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum Action {
     Keep,
     Reset,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum DefaultAction {
     Keep,
@@ -163,6 +224,49 @@ mod tests {
         assert!(selection.ids.contains(&id_from_ids));
     }
 
+    #[test]
+    fn spec_parses_expect_block() {
+        let yaml = "files: {}\nexpect:\n  commit_id: abc123\n  tree_id: sha256:ee\n  hunks: sha256:ff\n  paths: [a.txt]\n";
+        let spec = Spec::from_str(yaml).expect("spec should parse");
+        let expect = spec.expect.expect("expect block missing");
+
+        assert_eq!(expect.commit_id.as_deref(), Some("abc123"));
+        assert_eq!(expect.tree_id.as_deref(), Some("sha256:ee"));
+        assert_eq!(expect.operation_id, None);
+        assert_eq!(expect.hunks.as_deref(), Some("sha256:ff"));
+        assert_eq!(expect.paths, Some(vec!["a.txt".to_string()]));
+
+        let err = Spec::from_str(r#"{"expect": {"tree": "abc"}}"#);
+        assert!(err.is_err(), "unknown expect fields should be rejected");
+    }
+
+    #[test]
+    fn hunk_selector_accepts_listed_hunk_objects() {
+        let id = format!("{HUNK_ID_PREFIX}{}", "c".repeat(64));
+        let json = format!(
+            r#"{{"fuzzy": true, "files": {{"src/lib.rs": {{"hunks": [{{"id": "{id}", "index": 3, "type": "replace", "removed": "a\n", "added": "b\n", "context": {{"pre": "x\n", "post": ""}}}}]}}}}}}"#
+        );
+        let spec = Spec::from_str(&json).expect("spec should parse");
+        assert!(spec.fuzzy);
+
+        let selection = match spec.files.get("src/lib.rs") {
+            Some(FileSpec::Selection(selection)) => selection.to_selection(),
+            _ => panic!("expected selection spec"),
+        };
+
+        assert!(selection.ids.contains(&id));
+        assert!(selection.indices.is_empty());
+        assert_eq!(selection.patterns.len(), 1);
+        assert_eq!(selection.patterns[0].removed, "a\n");
+        assert_eq!(
+            selection.patterns[0]
+                .context
+                .as_ref()
+                .map(|c| c.before.as_str()),
+            Some("x\n")
+        );
+    }
+
     #[test]
     fn hunk_selector_string_index_parses() {
         let json = r#"{"files": {"src/lib.rs": {"hunks": ["1"]}}}"#;
@@ -0,0 +1,242 @@
+use crate::diff::Hunk;
+use anyhow::Result;
+
+/// File-level values a template can refer to.
+#[derive(Debug, Clone, Copy)]
+pub struct TemplateFile<'a> {
+    pub path: &'a str,
+    pub status: &'a str,
+    pub group: Option<&'a str>,
+    pub renamed_from: Option<&'a str>,
+    pub hunk_count: usize,
+    pub binary: bool,
+    pub truncated: bool,
+}
+
+/// A `list --template`: literal text with `{field}` placeholders.
+///
+/// `{{` and `}}` are literal braces, and `\n`, `\t` and `\\` are escapes so
+/// templates can be written on one command line. A template that mentions
+/// any `hunk.` field renders once per hunk, otherwise once per file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Path,
+    Status,
+    Group,
+    RenamedFrom,
+    HunkCount,
+    Binary,
+    Truncated,
+    HunkIndex,
+    HunkId,
+    HunkType,
+    HunkRemoved,
+    HunkAdded,
+    HunkBeforeStart,
+    HunkBeforeLines,
+    HunkAfterStart,
+    HunkAfterLines,
+    HunkTruncated,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("path", Field::Path),
+    ("status", Field::Status),
+    ("group", Field::Group),
+    ("rename.from", Field::RenamedFrom),
+    ("hunk_count", Field::HunkCount),
+    ("binary", Field::Binary),
+    ("truncated", Field::Truncated),
+    ("hunk.index", Field::HunkIndex),
+    ("hunk.id", Field::HunkId),
+    ("hunk.type", Field::HunkType),
+    ("hunk.removed", Field::HunkRemoved),
+    ("hunk.added", Field::HunkAdded),
+    ("hunk.before.start", Field::HunkBeforeStart),
+    ("hunk.before.lines", Field::HunkBeforeLines),
+    ("hunk.after.start", Field::HunkAfterStart),
+    ("hunk.after.lines", Field::HunkAfterLines),
+    ("hunk.truncated", Field::HunkTruncated),
+];
+
+impl Field {
+    fn is_hunk_field(self) -> bool {
+        matches!(
+            self,
+            Field::HunkIndex
+                | Field::HunkId
+                | Field::HunkType
+                | Field::HunkRemoved
+                | Field::HunkAdded
+                | Field::HunkBeforeStart
+                | Field::HunkBeforeLines
+                | Field::HunkAfterStart
+                | Field::HunkAfterLines
+                | Field::HunkTruncated
+        )
+    }
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = source.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => anyhow::bail!("Unclosed '{{' in template"),
+                        }
+                    }
+                    let name = name.trim();
+                    let field = FIELDS
+                        .iter()
+                        .find(|(known, _)| *known == name)
+                        .map(|(_, field)| *field)
+                        .ok_or_else(|| {
+                            let known: Vec<&str> = FIELDS.iter().map(|(name, _)| *name).collect();
+                            anyhow::anyhow!(
+                                "Unknown template field {{{name}}} (known fields: {})",
+                                known.join(", ")
+                            )
+                        })?;
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '}' => anyhow::bail!("Unmatched '}}' in template (use '}}}}' for a literal brace)"),
+                '\\' => match chars.next() {
+                    Some('n') => literal.push('\n'),
+                    Some('t') => literal.push('\t'),
+                    Some('\\') => literal.push('\\'),
+                    Some(other) => {
+                        literal.push('\\');
+                        literal.push(other);
+                    }
+                    None => literal.push('\\'),
+                },
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self { parts })
+    }
+
+    /// Whether the template renders once per hunk rather than once per file.
+    pub fn uses_hunks(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Field(field) if field.is_hunk_field()))
+    }
+
+    /// Render for one file, and for one of its hunks if the template uses them.
+    pub fn render(&self, file: &TemplateFile, hunk: Option<&Hunk>) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => out.push_str(text),
+                Part::Field(field) => out.push_str(&value(*field, file, hunk)),
+            }
+        }
+        out
+    }
+}
+
+fn value(field: Field, file: &TemplateFile, hunk: Option<&Hunk>) -> String {
+    let hunk_value = |f: fn(&Hunk) -> String| hunk.map(f).unwrap_or_default();
+    match field {
+        Field::Path => file.path.to_string(),
+        Field::Status => file.status.to_string(),
+        Field::Group => file.group.unwrap_or_default().to_string(),
+        Field::RenamedFrom => file.renamed_from.unwrap_or_default().to_string(),
+        Field::HunkCount => file.hunk_count.to_string(),
+        Field::Binary => file.binary.to_string(),
+        Field::Truncated => file.truncated.to_string(),
+        Field::HunkIndex => hunk_value(|hunk| hunk.index.to_string()),
+        Field::HunkId => hunk_value(|hunk| hunk.id.clone()),
+        Field::HunkType => hunk_value(|hunk| hunk.hunk_type.clone()),
+        Field::HunkRemoved => hunk_value(|hunk| hunk.removed.clone()),
+        Field::HunkAdded => hunk_value(|hunk| hunk.added.clone()),
+        Field::HunkBeforeStart => hunk_value(|hunk| hunk.before_range.start.to_string()),
+        Field::HunkBeforeLines => hunk_value(|hunk| hunk.before_range.length.to_string()),
+        Field::HunkAfterStart => hunk_value(|hunk| hunk.after_range.start.to_string()),
+        Field::HunkAfterLines => hunk_value(|hunk| hunk.after_range.length.to_string()),
+        Field::HunkTruncated => hunk_value(|hunk| (hunk.truncated == Some(true)).to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::get_hunks;
+
+    fn file(hunk_count: usize) -> TemplateFile<'static> {
+        TemplateFile {
+            path: "src/lib.rs",
+            status: "modified",
+            group: None,
+            renamed_from: None,
+            hunk_count,
+            binary: false,
+            truncated: false,
+        }
+    }
+
+    #[test]
+    fn renders_file_and_hunk_fields() {
+        let template = Template::parse("{status} {path}: {hunk_count} hunks\\n").unwrap();
+        assert!(!template.uses_hunks());
+        assert_eq!(
+            template.render(&file(2), None),
+            "modified src/lib.rs: 2 hunks\n"
+        );
+
+        let hunks = get_hunks("a\nb\n", "a\nB\n");
+        let template = Template::parse("{path}#{hunk.index} {hunk.id} +{hunk.added}").unwrap();
+        assert!(template.uses_hunks());
+        assert_eq!(
+            template.render(&file(1), Some(&hunks[0])),
+            format!("src/lib.rs#0 {} +B\n", hunks[0].id)
+        );
+    }
+
+    #[test]
+    fn escapes_braces_and_rejects_unknown_fields() {
+        let template = Template::parse("{{{path}}}\\t{ status }").unwrap();
+        assert_eq!(template.render(&file(0), None), "{src/lib.rs}\tmodified");
+
+        let err = Template::parse("{author}").unwrap_err().to_string();
+        assert!(err.contains("Unknown template field {author}"));
+        assert!(Template::parse("oops}").is_err());
+        assert!(Template::parse("{path").is_err());
+    }
+}
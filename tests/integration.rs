@@ -80,6 +80,31 @@ impl TestRepo {
             .expect("failed to run jj-hunk")
     }
 
+    fn hunk_with_stdin(&self, args: &[&str], input: &str) -> std::process::Output {
+        use std::io::Write;
+
+        let mut child = Command::new(jj_hunk_bin())
+            .args(args)
+            .current_dir(&self.dir)
+            .env("JJ_USER", "Test User")
+            .env("JJ_EMAIL", "test@example.com")
+            .env_remove("JJ_CONFIG")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .expect("failed to run jj-hunk");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        child
+            .wait_with_output()
+            .expect("failed to wait for jj-hunk")
+    }
+
     fn hunk_with_empty_config(&self, args: &[&str]) -> std::process::Output {
         let config = self.dir.join("empty-jj-config.toml");
         std::fs::write(&config, "").unwrap();
@@ -229,6 +254,115 @@ fn list_rev_files_mode() {
     assert!(out.contains("bar.txt"));
 }
 
+#[test]
+fn list_reads_added_removed_and_nested_files_in_one_pass() {
+    let repo = TestRepo::new("list-batched");
+
+    repo.write_file("gone.txt", "bye\n");
+    repo.write_file("src/deep/mod.rs", "fn a() {}\n");
+    repo.jj_ok(&["commit", "-m", "base"]);
+
+    std::fs::remove_file(repo.path().join("gone.txt")).unwrap();
+    repo.write_file("src/deep/mod.rs", "fn b() {}\n");
+    repo.write_file("new.txt", "hello\n");
+
+    let out = repo.hunk_ok(&["list"]);
+    let json: serde_json::Value = serde_json::from_str(&out).unwrap();
+    let files = json["files"].as_array().unwrap();
+    let hunk = |path: &str| {
+        files
+            .iter()
+            .find(|f| f["path"] == path)
+            .unwrap_or_else(|| panic!("{path} missing from:\n{out}"))["hunks"][0]
+            .clone()
+    };
+
+    assert_eq!(hunk("gone.txt")["removed"], "bye\n");
+    assert_eq!(hunk("new.txt")["added"], "hello\n");
+    assert_eq!(hunk("src/deep/mod.rs")["removed"], "fn a() {}\n");
+    assert_eq!(hunk("src/deep/mod.rs")["added"], "fn b() {}\n");
+}
+
+#[test]
+fn list_snapshots_the_working_copy_once() {
+    let repo = TestRepo::new("list-one-snapshot");
+
+    for name in ["a.txt", "b.txt", "c.txt", "d.txt"] {
+        repo.write_file(name, "one\n");
+    }
+    repo.jj_ok(&["commit", "-m", "base"]);
+    for name in ["a.txt", "b.txt", "c.txt", "d.txt"] {
+        repo.write_file(name, "two\n");
+    }
+
+    let ops = || {
+        repo.jj_ok(&[
+            "op",
+            "log",
+            "--ignore-working-copy",
+            "--no-graph",
+            "-T",
+            "id ++ \"\\n\"",
+        ])
+        .lines()
+        .count()
+    };
+    let before = ops();
+    let out = repo.hunk_ok(&["list"]);
+    assert_eq!(ops(), before + 1, "list should snapshot exactly once");
+
+    let json: serde_json::Value = serde_json::from_str(&out).unwrap();
+    for file in json["files"].as_array().unwrap() {
+        assert_eq!(file["hunks"][0]["added"], "two\n", "{out}");
+    }
+}
+
+#[test]
+fn cache_clear_removes_hunks_cached_by_list() {
+    let repo = TestRepo::new("cache-clear");
+
+    repo.write_file("a.txt", "one\n");
+    repo.jj_ok(&["commit", "-m", "base"]);
+    repo.write_file("a.txt", "two\n");
+
+    let first = repo.hunk_ok(&["list"]);
+    assert!(repo.path().join(".jj/jj-hunk/cache").is_dir());
+    assert_eq!(repo.hunk_ok(&["list"]), first);
+
+    let out = repo.hunk_ok(&["cache", "clear"]);
+    assert!(out.contains("Removed 1 cached hunk entries"), "{out}");
+    assert!(!repo.path().join(".jj/jj-hunk/cache").exists());
+}
+
+#[test]
+fn snapshot_copies_both_sides_into_dest() {
+    let root = std::env::temp_dir().join(format!("jj-hunk-test-snapshot-{}", std::process::id()));
+    let left = root.join("l");
+    let right = root.join("r");
+    let dest = root.join("out");
+    std::fs::create_dir_all(left.join("a/b")).unwrap();
+    std::fs::create_dir_all(&right).unwrap();
+    std::fs::write(left.join("a/b/c.txt"), "left\n").unwrap();
+    std::fs::write(right.join("d.txt"), "right\n").unwrap();
+
+    let out = Command::new(jj_hunk_bin())
+        .arg("snapshot")
+        .args([&left, &right, &dest])
+        .output()
+        .expect("failed to run jj-hunk");
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    let read = |path: &str| std::fs::read_to_string(dest.join(path)).unwrap();
+    assert_eq!(read("left/a/b/c.txt"), "left\n");
+    assert_eq!(read("right/d.txt"), "right\n");
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
 // ---------------------------------------------------------------------------
 // split -r
 // ---------------------------------------------------------------------------
@@ -447,6 +581,173 @@ fn commit_self_configures_jj_hunk_tool_when_user_config_is_empty() {
     );
 }
 
+// ---------------------------------------------------------------------------
+// spec expectations
+// ---------------------------------------------------------------------------
+
+#[test]
+fn commit_refuses_spec_with_stale_expectation() {
+    let repo = TestRepo::new("commit-stale-expect");
+
+    repo.write_file("a.txt", "aaa\n");
+    repo.jj_ok(&["commit", "-m", "base"]);
+
+    repo.write_file("a.txt", "AAA\n");
+    let template = repo.hunk_ok(&["list", "--spec-template"]);
+    assert!(template.contains("\"expect\""), "template: {}", template);
+    assert!(template.contains("\"commit_id\""), "template: {}", template);
+
+    // Working copy changes after the spec was written
+    repo.write_file("a.txt", "AAA!\n");
+    let err = repo.hunk_fail(&["commit", &template, "stale"]);
+    assert!(err.contains("re-list"), "should reject stale spec: {}", err);
+
+    let fresh = repo.hunk_ok(&["list", "--spec-template"]);
+    repo.hunk_ok(&["commit", &fresh, "fresh"]);
+
+    let log = repo.log_descriptions();
+    assert!(log.iter().any(|d| d == "fresh"), "log: {:?}", log);
+    assert!(!log.iter().any(|d| d == "stale"), "log: {:?}", log);
+}
+
+// ---------------------------------------------------------------------------
+// undo
+// ---------------------------------------------------------------------------
+
+#[test]
+fn undo_restores_state_before_split() {
+    let repo = TestRepo::new("undo-split");
+
+    repo.write_file("a.txt", "aaa\n");
+    repo.jj_ok(&["commit", "-m", "base"]);
+
+    repo.write_file("a.txt", "AAA\n");
+    repo.write_file("b.txt", "BBB\n");
+
+    let spec = r#"{"files": {"a.txt": {"action": "keep"}}, "default": "reset"}"#;
+    repo.hunk_ok(&["split", spec, "a changes"]);
+    assert!(repo.log_descriptions().iter().any(|d| d == "a changes"));
+
+    repo.hunk_ok(&["undo"]);
+
+    let log = repo.log_descriptions();
+    assert!(!log.iter().any(|d| d == "a changes"), "log: {:?}", log);
+    let wc_files = repo.changed_files("@");
+    assert!(
+        wc_files.iter().any(|f| f.contains("a.txt")),
+        "{:?}",
+        wc_files
+    );
+    assert!(
+        wc_files.iter().any(|f| f.contains("b.txt")),
+        "{:?}",
+        wc_files
+    );
+
+    let err = repo.hunk_fail(&["undo"]);
+    assert!(err.contains("Nothing to undo"), "{}", err);
+}
+
+#[test]
+fn undo_refuses_after_other_operations_unless_forced() {
+    let repo = TestRepo::new("undo-force");
+
+    repo.write_file("a.txt", "aaa\n");
+    repo.jj_ok(&["commit", "-m", "base"]);
+
+    repo.write_file("a.txt", "AAA\n");
+    repo.write_file("b.txt", "BBB\n");
+
+    let spec = r#"{"files": {"a.txt": {"action": "keep"}}, "default": "reset"}"#;
+    repo.hunk_ok(&["commit", spec, "commit a only"]);
+    repo.jj_ok(&["describe", "-m", "wip"]);
+
+    let err = repo.hunk_fail(&["undo"]);
+    assert!(err.contains("--force"), "should refuse: {}", err);
+    assert!(repo.log_descriptions().iter().any(|d| d == "commit a only"));
+
+    repo.hunk_ok(&["undo", "--force"]);
+    let log = repo.log_descriptions();
+    assert!(!log.iter().any(|d| d == "commit a only"), "log: {:?}", log);
+}
+
+// ---------------------------------------------------------------------------
+// check / serve
+// ---------------------------------------------------------------------------
+
+#[test]
+fn check_reports_unknown_ids_and_missing_files() {
+    let repo = TestRepo::new("check-report");
+
+    repo.write_file("a.txt", "aaa\n");
+    repo.jj_ok(&["commit", "-m", "base"]);
+    repo.write_file("a.txt", "AAA\n");
+
+    let good = r#"{"files": {"a.txt": {"hunks": [0]}}}"#;
+    let report: serde_json::Value = serde_json::from_str(&repo.hunk_ok(&["check", good])).unwrap();
+    assert_eq!(report["ok"], true, "{}", report);
+    assert_eq!(report["files"][0]["selected"], 1, "{}", report);
+
+    let bad = format!(
+        r#"{{"files": {{"a.txt": {{"ids": ["hunk-{}"]}}, "gone.txt": {{"action": "keep"}}}}}}"#,
+        "0".repeat(64)
+    );
+    let out = repo.hunk(&["check", &bad]);
+    assert!(!out.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(report["ok"], false, "{}", report);
+    assert_eq!(
+        report["files"][0]["unknown_ids"].as_array().unwrap().len(),
+        1
+    );
+    assert_eq!(report["files"][1]["missing"], true, "{}", report);
+}
+
+#[test]
+fn serve_answers_list_and_commit_requests() {
+    let repo = TestRepo::new("serve");
+
+    repo.write_file("a.txt", "aaa\n");
+    repo.jj_ok(&["commit", "-m", "base"]);
+    repo.write_file("a.txt", "AAA\n");
+    repo.write_file("b.txt", "BBB\n");
+
+    let requests = [
+        r#"{"jsonrpc": "2.0", "id": 1, "method": "list", "params": {"mode": "files"}}"#,
+        r#"{"jsonrpc": "2.0", "id": 2, "method": "commit", "params": {"spec": {"files": {"a.txt": {"action": "keep"}}, "default": "reset"}, "message": "via serve"}}"#,
+        r#"{"jsonrpc": "2.0", "id": 3, "method": "list", "params": {"mode": "files"}}"#,
+    ]
+    .join("\n");
+
+    let out = repo.hunk_with_stdin(&["serve"], &requests);
+    assert!(
+        out.status.success(),
+        "serve failed: {}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    let responses: Vec<serde_json::Value> = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("response should be JSON"))
+        .collect();
+    assert_eq!(responses.len(), 3, "{:?}", responses);
+
+    let paths = |response: &serde_json::Value| -> Vec<String> {
+        response["result"]["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file["path"].as_str().unwrap().to_string())
+            .collect()
+    };
+    assert_eq!(paths(&responses[0]), vec!["a.txt", "b.txt"]);
+    assert_eq!(responses[1]["result"]["ok"], true, "{:?}", responses[1]);
+    assert_eq!(paths(&responses[2]), vec!["b.txt"]);
+
+    let log = repo.log_descriptions();
+    assert!(log.iter().any(|d| d == "via serve"), "log: {:?}", log);
+}
+
 // ---------------------------------------------------------------------------
 // error cases
 // ---------------------------------------------------------------------------